@@ -7,7 +7,6 @@ use panic_halt as _;
 
 use ch32v00x_hal as hal;
 use ch32v00x_hal::prelude::*;
-use qingke::riscv;
 
 #[qingke_rt::entry]
 fn main() -> ! {
@@ -0,0 +1,44 @@
+//! Continuously sample two pins and the internal reference into a DMA ring buffer.
+#![no_std]
+#![no_main]
+
+use hal::println;
+use panic_halt as _;
+
+use ch32v00x_hal as hal;
+use hal::adc::{Adc, ExternalTrigger, Vref};
+use hal::dma::{CircReadDma, DmaExt};
+use hal::prelude::*;
+
+// Each half holds two complete sequences of three conversions
+static mut BUF: [[u16; 6]; 2] = [[0; 6]; 2];
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    hal::debug::SDIPrint::enable();
+
+    let p = ch32v0::ch32v003::Peripherals::take().unwrap();
+
+    let mut rcc = p.RCC.constrain();
+    let clocks = rcc.config.freeze();
+
+    let gpioa = p.GPIOA.split(&mut rcc);
+    let gpiod = p.GPIOD.split(&mut rcc);
+    let channels = p.DMA1.split(&mut rcc);
+
+    let a0 = gpioa.pa2.into_analog();
+    let a7 = gpiod.pd4.into_analog();
+
+    let adc = Adc::new(p.ADC1, &clocks);
+    let adc_dma = adc.with_scan_dma((a0, a7, Vref), ExternalTrigger::Software, channels.1);
+
+    // SAFETY: BUF is only accessed through this reference
+    let mut circ = adc_dma.circ_read(unsafe { &mut *core::ptr::addr_of_mut!(BUF) });
+
+    loop {
+        match circ.peek(|half, _| [half[0], half[1], half[2]]) {
+            Ok([a0, a7, vref]) => println!("a0 {} a7 {} vref {}", a0, a7, vref),
+            Err(_) => println!("overrun"),
+        }
+    }
+}
@@ -21,8 +21,6 @@ fn main() -> ! {
     loop {
         led.toggle();
 
-        unsafe {
-            qingke::riscv::asm::delay(10000000);
-        }
+        qingke::riscv::asm::delay(10000000);
     }
 }
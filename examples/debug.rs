@@ -28,8 +28,6 @@ fn main() -> ! {
         led.toggle();
         println!("led toggle");
 
-        unsafe {
-            riscv::asm::delay(10000000);
-        }
+        riscv::asm::delay(10000000);
     }
 }
@@ -0,0 +1,37 @@
+//! Copy a buffer with DMA1 channel 1 in memory-to-memory mode.
+#![no_std]
+#![no_main]
+
+use hal::println;
+use panic_halt as _;
+
+use ch32v0::ch32v003 as pac;
+use ch32v00x_hal as hal;
+
+use hal::{dma::DmaExt, prelude::*};
+
+static SRC: [u32; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+static mut DST: [u32; 8] = [0; 8];
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    hal::debug::SDIPrint::enable();
+
+    let p = pac::Peripherals::take().unwrap();
+
+    let mut rcc = p.RCC.constrain();
+    let _clocks = rcc.config.freeze();
+
+    let channels = p.DMA1.split(&mut rcc);
+
+    // SAFETY: DST is only accessed through this reference
+    let dst = unsafe { &mut *core::ptr::addr_of_mut!(DST) };
+    let transfer = channels.1.copy(&SRC, dst);
+    let ((_, dst), _) = transfer.wait();
+
+    println!("copied {:?}", dst);
+
+    loop {
+        qingke::riscv::asm::wfi();
+    }
+}
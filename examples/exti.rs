@@ -0,0 +1,36 @@
+//! Toggle an LED on every falling edge of a button on PC0.
+//!
+//! The EXTI pending flag is polled here; enable the EXTI7_0 interrupt to handle it
+//! asynchronously instead.
+#![no_std]
+#![no_main]
+
+use panic_halt as _;
+
+use ch32v00x_hal::gpio::Edge;
+use ch32v00x_hal::prelude::*;
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    let mut p = ch32v0::ch32v003::Peripherals::take().unwrap();
+
+    let mut rcc = p.RCC.constrain();
+    let _clocks = rcc.config.freeze();
+
+    let gpioc = p.GPIOC.split(&mut rcc);
+    let gpiod = p.GPIOD.split(&mut rcc);
+
+    let mut led = gpiod.pd6.into_push_pull_output();
+
+    let mut button = gpioc.pc0.into_pull_up_input();
+    button.make_interrupt_source(&mut p.AFIO, &mut rcc);
+    button.trigger_on_edge(&mut p.EXTI, Edge::Falling);
+    button.enable_interrupt(&mut p.EXTI);
+
+    loop {
+        if button.check_interrupt() {
+            button.clear_interrupt_pending_bit();
+            led.toggle();
+        }
+    }
+}
@@ -41,5 +41,7 @@ fn main() -> ! {
         writeln!(display, "{i}").unwrap();
     }
 
-    loop {}
+    loop {
+        qingke::riscv::asm::wfi();
+    }
 }
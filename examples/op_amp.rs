@@ -16,6 +16,8 @@ fn main() -> ! {
     let mut rcc = p.RCC.constrain();
     let _clocks = rcc.config.freeze();
 
+    let mut extend = p.EXTEND.constrain();
+
     let gpioa = p.GPIOA.split(&mut rcc);
     let gpiod = p.GPIOD.split(&mut rcc);
 
@@ -32,10 +34,13 @@ fn main() -> ! {
     let output_pin = gpiod.pd4;
 
     #[allow(unused)]
-    let opa = hal::extend::opa::OpAmp::enable(non_inverting_pin, inverting_pin, output_pin);
+    let opa =
+        hal::extend::opa::OpAmp::enable(&mut extend, non_inverting_pin, inverting_pin, output_pin);
 
     // Pins are available for other uses after disabling opa.
-    // let (non_inverting_pin, inverting_pin, output_pin) = opa.disable();
+    // let (non_inverting_pin, inverting_pin, output_pin) = opa.disable(&mut extend);
 
-    loop {}
+    loop {
+        qingke::riscv::asm::wfi();
+    }
 }
@@ -0,0 +1,35 @@
+//! Release PD7 from its nRST function and use it to blink an LED.
+#![no_std]
+#![no_main]
+
+use panic_halt as _;
+
+use ch32v00x_hal as hal;
+use hal::flash::{Flash, ResetMode};
+use hal::prelude::*;
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    let p = ch32v0::ch32v003::Peripherals::take().unwrap();
+
+    let mut flash = Flash::new(p.FLASH);
+    let mut options = flash.read_option_bytes();
+    if options.user.reset_mode != ResetMode::Gpio {
+        options.user.reset_mode = ResetMode::Gpio;
+        flash.write_option_bytes(&options).unwrap();
+        // The option bytes are loaded on reset
+        hal::rcc::system_reset();
+    }
+
+    let mut rcc = p.RCC.constrain();
+    let _clocks = rcc.config.freeze();
+
+    let gpiod = p.GPIOD.split(&mut rcc);
+    let mut led = gpiod.pd7.into_push_pull_output();
+
+    loop {
+        led.toggle();
+
+        qingke::riscv::asm::delay(10000000);
+    }
+}
@@ -0,0 +1,48 @@
+//! Echo bytes received on USART1 using DMA for both directions.
+#![no_std]
+#![no_main]
+
+use panic_halt as _;
+
+use ch32v00x_hal as hal;
+
+use hal::dma::{DmaExt, WriteDma};
+use hal::prelude::*;
+use hal::serial::Config;
+
+static mut RX_BUF: [u8; 64] = [0; 64];
+static mut TX_BUF: [u8; 16] = [0; 16];
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    let p = ch32v0::ch32v003::Peripherals::take().unwrap();
+
+    let mut rcc = p.RCC.constrain();
+    let clocks = rcc.config.freeze();
+
+    let gpiod = p.GPIOD.split(&mut rcc);
+    let channels = p.DMA1.split(&mut rcc);
+
+    let tx = gpiod.pd5.into_alternate();
+    let rx = gpiod.pd6.into_floating_input();
+
+    let usart = p.USART1.usart(tx, rx, Config::default(), &mut rcc, &clocks);
+    let (mut tx, rx) = usart.with_dma(channels.4, channels.5);
+
+    // SAFETY: RX_BUF is only accessed through this reference
+    let mut ring = rx.ring(unsafe { &mut *core::ptr::addr_of_mut!(RX_BUF) });
+
+    loop {
+        // SAFETY: TX_BUF is only accessed here, after the previous transfer is over
+        let tx_buf = unsafe { &mut *core::ptr::addr_of_mut!(TX_BUF) };
+
+        // Bytes lost to an overrun are skipped
+        let len = ring.read(tx_buf).unwrap_or(0);
+        if len == 0 {
+            continue;
+        }
+
+        let (data, _) = tx_buf.split_at_mut(len);
+        (_, tx) = tx.write(data).wait();
+    }
+}
@@ -0,0 +1,47 @@
+//! Exchange a few bytes over SPI1 in master mode.
+//!
+//! Connect MOSI (PC6) to MISO (PC7) to read back what is sent.
+#![no_std]
+#![no_main]
+
+use embedded_hal_1::spi::SpiBus;
+use hal::println;
+use panic_halt as _;
+
+use ch32v0::ch32v003 as pac;
+use ch32v00x_hal as hal;
+
+use hal::{prelude::*, spi::*};
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    hal::debug::SDIPrint::enable();
+
+    let p = pac::Peripherals::take().unwrap();
+
+    let mut rcc = p.RCC.constrain();
+    let clocks = rcc.config.freeze();
+
+    let gpioc = p.GPIOC.split(&mut rcc);
+
+    // SPI pins
+    let sck = gpioc.pc5.into_alternate();
+    let miso = gpioc.pc7.into_floating_input();
+    let mosi = gpioc.pc6.into_alternate();
+
+    let config = SpiConfig {
+        frequency: 4.MHz(),
+        ..SpiConfig::default()
+    };
+    let mut spi = Spi::spi1(p.SPI1, sck, miso, mosi, config, &mut rcc, &clocks);
+
+    let mut buf = [0xde, 0xad, 0xbe, 0xef];
+    spi.transfer_in_place(&mut buf).unwrap();
+    spi.flush().unwrap();
+
+    println!("received {:02x?}", buf);
+
+    loop {
+        qingke::riscv::asm::wfi();
+    }
+}
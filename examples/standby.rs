@@ -0,0 +1,28 @@
+//! Blink an LED, spending the time in between in standby, woken up by the AWU.
+#![no_std]
+#![no_main]
+
+use panic_halt as _;
+
+use ch32v00x_hal as hal;
+use hal::prelude::*;
+use hal::pwr::{Pwr, WaitMode};
+
+#[qingke_rt::entry]
+fn main() -> ! {
+    let mut p = ch32v0::ch32v003::Peripherals::take().unwrap();
+
+    let mut rcc = p.RCC.constrain();
+    let _clocks = rcc.config.freeze();
+
+    let gpiod = p.GPIOD.split(&mut rcc);
+    let mut led = gpiod.pd6.into_push_pull_output();
+
+    let mut pwr = Pwr::pwr(p.PWR, &mut rcc);
+    pwr.enable_awu(500.millis(), &mut p.EXTI);
+
+    loop {
+        led.toggle();
+        let _clocks = pwr.standby(WaitMode::Wfe);
+    }
+}
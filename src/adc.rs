@@ -1,7 +1,14 @@
 use embedded_hal_02::adc::{Channel, OneShot};
+use crate::dma::{
+    dma1::C1, CircBuffer, CircReadDma, DataSize, Direction, Priority, ReadDma, Receive, RxDma,
+    Transfer, TransferPayload, W,
+};
 use crate::gpio::{self, Analog};
 use crate::pac;
 use crate::rcc::{self, Clocks, Enable, Reset};
+use core::marker::PhantomData;
+use core::sync::atomic::{self, Ordering};
+use embedded_dma::WriteBuffer;
 use qingke::riscv::asm::delay;
 use fugit::{Hertz, HertzU32};
 
@@ -113,6 +120,38 @@ adc_pins!(pac::ADC1,
     gpio::PD4<Analog> => 7_u8,
 );
 
+/// Internal reference voltage channel
+pub struct Vref;
+
+/// Internal calibration voltage channel
+pub struct Vcal;
+
+adc_pins!(pac::ADC1,
+    Vref => 8_u8,
+    Vcal => 9_u8,
+);
+
+/// Source that starts the conversion of the regular sequence
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExternalTrigger {
+    /// TIM1 TRGO event
+    Tim1Trgo = 0b000,
+    /// TIM1 capture/compare 1
+    Tim1Cc1 = 0b001,
+    /// TIM1 capture/compare 2
+    Tim1Cc2 = 0b010,
+    /// TIM2 TRGO event
+    Tim2Trgo = 0b011,
+    /// TIM2 capture/compare 1
+    Tim2Cc1 = 0b100,
+    /// TIM2 capture/compare 2
+    Tim2Cc2 = 0b101,
+    /// External pin, PD3 or PC2 depending on the AFIO remap
+    Pin = 0b110,
+    /// Software start, the sequence is then converted back to back
+    Software = 0b111,
+}
+
 /// Stored ADC config can be restored using the `Adc::restore_cfg` method
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
 pub struct StoredConfig(SampleTime, Align);
@@ -142,7 +181,7 @@ impl Adc<pac::ADC1> {
             let two_adc_cycles = clocks.sysclk() / clocks.adcclk() * 2;
             let already_delayed = clocks.sysclk() / HertzU32::kHz(800);
             if two_adc_cycles > already_delayed {
-                unsafe { delay(two_adc_cycles - already_delayed) };
+                delay(two_adc_cycles - already_delayed);
             }
         }
         s.calibrate();
@@ -185,10 +224,11 @@ impl Adc<pac::ADC1> {
     /// Returns the largest possible sample value for the current settings
     pub fn max_sample(&self) -> u16 {
         match self.align {
-            Align::Left => u16::max_value(),
+            Align::Left => u16::MAX,
             Align::Right => (1 << 10) - 1,
         }
     }
+
     #[inline(always)]
     pub fn set_external_trigger(&mut self, trigger: u8) {
         self.rb.ctlr2.modify(|_, w| w.extsel().variant(trigger))
@@ -200,7 +240,7 @@ impl Adc<pac::ADC1> {
         // this time can be found in the datasheets.
         // Here we are delaying for approximately 1us, considering 1.25 instructions per
         // cycle. Do we support a chip which needs more than 1us ?
-        unsafe { delay(self.sysclk / HertzU32::kHz(800)) };
+        delay(self.sysclk / HertzU32::kHz(800));
     }
 
     fn power_down(&mut self) {
@@ -355,7 +395,7 @@ impl Adc<pac::ADC1> {
             // sensor, this time can be found in the datasheets.
             // Here we are delaying for approximately 10us, considering 1.25 instructions per
             // cycle. Do we support a chip which needs more than 10us ?
-            unsafe { delay(self.sysclk.raw() / 80_000) };
+            delay(self.sysclk.raw() / 80_000);
             true
         } else {
             false
@@ -426,3 +466,242 @@ where
         Ok(res.into())
     }
 }
+
+/// Set the regular sequence from a tuple of channels
+pub trait SetChannels<PINS>: ChannelTimeSequence {
+    /// Set the sample time of every channel in the tuple
+    fn set_samples(&mut self);
+    /// Set the tuple as the regular sequence, in order
+    fn set_sequence(&mut self);
+}
+
+macro_rules! scan_sequence {
+    ($($PIN:ident),+) => {
+        impl<$($PIN),+> SetChannels<($($PIN,)+)> for Adc<pac::ADC1>
+        where
+            $($PIN: Channel<pac::ADC1, ID = u8>),+
+        {
+            fn set_samples(&mut self) {
+                $(self.set_channel_sample_time($PIN::channel(), self.sample_time);)+
+            }
+
+            fn set_sequence(&mut self) {
+                self.set_regular_sequence(&[$($PIN::channel()),+]);
+            }
+        }
+    };
+}
+
+scan_sequence!(A, B);
+scan_sequence!(A, B, C);
+scan_sequence!(A, B, C, D);
+scan_sequence!(A, B, C, D, E);
+scan_sequence!(A, B, C, D, E, F);
+scan_sequence!(A, B, C, D, E, F, G);
+scan_sequence!(A, B, C, D, E, F, G, H);
+scan_sequence!(A, B, C, D, E, F, G, H, I);
+scan_sequence!(A, B, C, D, E, F, G, H, I, J);
+
+pub struct AdcPayload<PINS, MODE> {
+    adc: Adc<pac::ADC1>,
+    pins: PINS,
+    trigger: ExternalTrigger,
+    _mode: PhantomData<MODE>,
+}
+
+/// ADC streaming its conversions through DMA1 channel 1
+pub type AdcDma<PINS, MODE> = RxDma<AdcPayload<PINS, MODE>, C1>;
+
+impl Adc<pac::ADC1> {
+    /// Continuously convert a single channel into DMA1 channel 1
+    pub fn with_dma<PIN>(mut self, pin: PIN, dma_ch: C1) -> AdcDma<PIN, Continuous>
+    where
+        PIN: Channel<pac::ADC1, ID = u8>,
+    {
+        self.rb.ctlr1.modify(|_, w| w.discen().clear_bit());
+        self.rb.ctlr2.modify(|_, w| unsafe {
+            w.align()
+                .bit(self.align.into())
+                .exttrig()
+                .set_bit()
+                .extsel()
+                .bits(ExternalTrigger::Software as u8)
+        });
+        self.set_channel_sample_time(PIN::channel(), self.sample_time);
+        self.rb.rsqr3.modify(|_, w| unsafe { w.sq1().bits(PIN::channel()) });
+        self.rb.rsqr1.modify(|_, w| unsafe { w.l().bits(0b0) });
+        self.rb.ctlr2.modify(|_, w| w.dma().set_bit());
+
+        let payload = AdcPayload {
+            adc: self,
+            pins: pin,
+            trigger: ExternalTrigger::Software,
+            _mode: PhantomData,
+        };
+        RxDma {
+            payload,
+            channel: dma_ch,
+        }
+    }
+
+    /// Convert a sequence of channels in scan mode into DMA1 channel 1
+    ///
+    /// `pins` is a tuple of up to 10 channels, see [`SetChannels`]. With
+    /// [`ExternalTrigger::Software`] the sequence is converted back to back as soon as
+    /// the transfer starts, otherwise every trigger event converts the sequence once.
+    pub fn with_scan_dma<PINS>(
+        mut self,
+        pins: PINS,
+        trigger: ExternalTrigger,
+        dma_ch: C1,
+    ) -> AdcDma<PINS, Scan>
+    where
+        Self: SetChannels<PINS>,
+    {
+        // The sequence is reprogrammed with the ADC powered up, as in `with_dma`
+        self.rb.ctlr2.modify(|_, w| unsafe {
+            w.dma()
+                .clear_bit()
+                .cont()
+                .clear_bit()
+                .align()
+                .bit(self.align.into())
+                .exttrig()
+                .set_bit()
+                .extsel()
+                .bits(trigger as u8)
+        });
+        self.rb
+            .ctlr1
+            .modify(|_, w| w.scan().set_bit().discen().clear_bit());
+        self.set_samples();
+        self.set_sequence();
+        self.rb.ctlr2.modify(|_, w| w.dma().set_bit());
+
+        let payload = AdcPayload {
+            adc: self,
+            pins,
+            trigger,
+            _mode: PhantomData,
+        };
+        RxDma {
+            payload,
+            channel: dma_ch,
+        }
+    }
+}
+
+impl<PINS, MODE> AdcDma<PINS, MODE>
+where
+    Self: TransferPayload,
+{
+    /// Stop the conversions and give back the ADC, the pins and the DMA channel
+    ///
+    /// The ADC is switched back to one-shot mode.
+    pub fn split(mut self) -> (Adc<pac::ADC1>, PINS, C1) {
+        self.stop();
+
+        let RxDma { payload, channel } = self;
+        let mut adc = payload.adc;
+        adc.rb.ctlr2.modify(|_, w| w.dma().clear_bit());
+        adc.rb.ctlr1.modify(|_, w| w.scan().clear_bit());
+        adc.setup_oneshot();
+
+        (adc, payload.pins, channel)
+    }
+
+    /// Configure the channel to read from the data register into `address`
+    fn configure(&mut self, address: u32, len: usize, circular: bool) {
+        self.channel.set_peripheral_address(
+            unsafe { &(*pac::ADC1::ptr()).rdatar as *const _ as u32 },
+            false,
+        );
+        self.channel.set_memory_address(address, true);
+        self.channel.set_transfer_length(len);
+
+        atomic::compiler_fence(Ordering::Release);
+
+        self.channel.set_mem2mem(false);
+        self.channel.set_priority(Priority::Medium);
+        self.channel
+            .set_data_size(DataSize::Bits16, DataSize::Bits16);
+        self.channel.set_circular(circular);
+        self.channel.set_direction(Direction::PeripheralToMemory);
+    }
+}
+
+impl<PINS> TransferPayload for AdcDma<PINS, Continuous> {
+    fn start(&mut self) {
+        self.channel.start();
+        let rb = &self.payload.adc.rb;
+        rb.ctlr2.modify(|_, w| w.cont().set_bit());
+        rb.ctlr2.modify(|_, w| w.swstart().set_bit());
+    }
+
+    fn stop(&mut self) {
+        self.channel.stop();
+        self.payload
+            .adc
+            .rb
+            .ctlr2
+            .modify(|_, w| w.cont().clear_bit());
+    }
+}
+
+impl<PINS> TransferPayload for AdcDma<PINS, Scan> {
+    fn start(&mut self) {
+        self.channel.start();
+        let rb = &self.payload.adc.rb;
+        if self.payload.trigger == ExternalTrigger::Software {
+            rb.ctlr2.modify(|_, w| w.cont().set_bit());
+            rb.ctlr2.modify(|_, w| w.swstart().set_bit());
+        }
+    }
+
+    fn stop(&mut self) {
+        self.channel.stop();
+        self.payload
+            .adc
+            .rb
+            .ctlr2
+            .modify(|_, w| w.cont().clear_bit());
+    }
+}
+
+impl<PINS, MODE> Receive for AdcDma<PINS, MODE> {
+    type RxChannel = C1;
+    type TransmittedWord = u16;
+}
+
+impl<B, PINS, MODE> CircReadDma<B, u16> for AdcDma<PINS, MODE>
+where
+    Self: TransferPayload,
+    &'static mut [B; 2]: WriteBuffer<Word = u16>,
+    B: 'static,
+{
+    fn circ_read(mut self, mut buffer: &'static mut [B; 2]) -> CircBuffer<B, Self> {
+        // NOTE(unsafe) We own the buffer now and we won't call other `&mut` on it
+        // until the end of the transfer.
+        let (ptr, len) = unsafe { buffer.write_buffer() };
+        self.configure(ptr as u32, len, true);
+        self.start();
+
+        CircBuffer::new(buffer, self)
+    }
+}
+
+impl<B, PINS, MODE> ReadDma<B, u16> for AdcDma<PINS, MODE>
+where
+    Self: TransferPayload,
+    B: WriteBuffer<Word = u16>,
+{
+    fn read(mut self, mut buffer: B) -> Transfer<W, B, Self> {
+        // NOTE(unsafe) We own the buffer now and we won't call other `&mut` on it
+        // until the end of the transfer.
+        let (ptr, len) = unsafe { buffer.write_buffer() };
+        self.configure(ptr as u32, len, false);
+        self.start();
+
+        Transfer::w(buffer, self)
+    }
+}
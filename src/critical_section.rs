@@ -1,6 +1,10 @@
 use critical_section::{set_impl, Impl, RawRestoreState};
 use qingke::register::gintenr;
 
+/// Masks interrupts globally through MIE (mirrored in GINTENR bit 3)
+///
+/// This also covers the interrupts assigned to VTF slots, which bypass the vector
+/// table but not the global interrupt enable.
 struct SingleHartCriticalSection;
 set_impl!(SingleHartCriticalSection);
 
@@ -22,9 +22,7 @@ impl embedded_hal_1::delay::DelayNs for CycleDelay {
         // The QingKe RISC-V2A executes an addi in 2 cycles
         let cycles = ns as u64 * self.rate.to_Hz() as u64 / 2_000_000_000;
 
-        unsafe {
-            qingke::riscv::asm::delay(cycles as u32);
-        }
+        qingke::riscv::asm::delay(cycles as u32);
     }
 
     fn delay_us(&mut self, us: u32) {
@@ -32,9 +30,7 @@ impl embedded_hal_1::delay::DelayNs for CycleDelay {
         // The QingKe RISC-V2A executes an addi in 2 cycles
         let cycles = us as u64 * self.rate.to_Hz() as u64 / 2_000_000;
 
-        unsafe {
-            qingke::riscv::asm::delay(cycles as u32);
-        }
+        qingke::riscv::asm::delay(cycles as u32);
     }
 }
 
@@ -0,0 +1,644 @@
+//! # Direct Memory Access
+//!
+//! DMA1 is split into its 7 channels with [`DmaExt::split`]. Peripheral drivers wrap a
+//! channel into an [`RxDma`] or [`TxDma`] payload, whose transfers are
+//! started with the [`ReadDma`], [`WriteDma`] and [`CircReadDma`] traits. Buffers must
+//! be `'static`, so they can not be freed or reused while the DMA is still accessing them.
+
+use core::{
+    marker::PhantomData,
+    sync::atomic::{compiler_fence, Ordering},
+};
+use embedded_dma::{ReadBuffer, WriteBuffer};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The DMA wrote to data that was not read yet
+    Overrun,
+}
+
+/// Channel interrupt events
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// First half of the buffer has been transferred
+    HalfTransfer,
+    /// The whole buffer has been transferred
+    TransferComplete,
+    /// A bus error occurred while accessing an address
+    TransferError,
+}
+
+/// Halves of a [`CircBuffer`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Half {
+    First,
+    Second,
+}
+
+/// Channel priority, used by the arbiter when several channels have pending requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Priority {
+    Low = 0b00,
+    Medium = 0b01,
+    High = 0b10,
+    VeryHigh = 0b11,
+}
+
+/// Width of a single transferred data item
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DataSize {
+    Bits8 = 0b00,
+    Bits16 = 0b01,
+    Bits32 = 0b10,
+}
+
+impl DataSize {
+    /// Data size matching a word type
+    pub(crate) const fn of<WORD>() -> Self {
+        match core::mem::size_of::<WORD>() {
+            1 => DataSize::Bits8,
+            2 => DataSize::Bits16,
+            _ => DataSize::Bits32,
+        }
+    }
+}
+
+/// Transfer direction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the peripheral address, write to the memory address
+    PeripheralToMemory,
+    /// Read from the memory address, write to the peripheral address
+    MemoryToPeripheral,
+}
+
+/// Double buffer that is continuously filled by a circular transfer
+pub struct CircBuffer<BUFFER, PAYLOAD>
+where
+    BUFFER: 'static,
+{
+    buffer: &'static mut [BUFFER; 2],
+    payload: PAYLOAD,
+    readable_half: Half,
+}
+
+impl<BUFFER, PAYLOAD> CircBuffer<BUFFER, PAYLOAD>
+where
+    &'static mut [BUFFER; 2]: WriteBuffer,
+    BUFFER: 'static,
+{
+    /// Wrap a circular transfer into `buf`, started by `payload`
+    pub fn new(buf: &'static mut [BUFFER; 2], payload: PAYLOAD) -> Self {
+        CircBuffer {
+            buffer: buf,
+            payload,
+            readable_half: Half::Second,
+        }
+    }
+}
+
+pub trait DmaExt {
+    type Channels;
+
+    /// Enables the DMA clock and splits it into independent channels
+    fn split(self, rcc: &mut crate::rcc::Rcc) -> Self::Channels;
+}
+
+pub trait TransferPayload {
+    fn start(&mut self);
+    fn stop(&mut self);
+}
+
+/// An ongoing DMA transfer, owning its buffer and payload until it is waited for
+pub struct Transfer<MODE, BUFFER, PAYLOAD>
+where
+    PAYLOAD: TransferPayload,
+{
+    _mode: PhantomData<MODE>,
+    buffer: BUFFER,
+    payload: PAYLOAD,
+}
+
+impl<BUFFER, PAYLOAD> Transfer<R, BUFFER, PAYLOAD>
+where
+    PAYLOAD: TransferPayload,
+{
+    /// Wrap a transfer that reads from memory into a peripheral, started by `payload`
+    pub fn r(buffer: BUFFER, payload: PAYLOAD) -> Self {
+        Transfer {
+            _mode: PhantomData,
+            buffer,
+            payload,
+        }
+    }
+}
+
+impl<BUFFER, PAYLOAD> Transfer<W, BUFFER, PAYLOAD>
+where
+    PAYLOAD: TransferPayload,
+{
+    /// Wrap a transfer that writes from a peripheral into memory, started by `payload`
+    pub fn w(buffer: BUFFER, payload: PAYLOAD) -> Self {
+        Transfer {
+            _mode: PhantomData,
+            buffer,
+            payload,
+        }
+    }
+}
+
+impl<MODE, BUFFER, PAYLOAD> Drop for Transfer<MODE, BUFFER, PAYLOAD>
+where
+    PAYLOAD: TransferPayload,
+{
+    fn drop(&mut self) {
+        self.payload.stop();
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// Read transfer
+pub struct R;
+
+/// Write transfer
+pub struct W;
+
+/// Memory to memory transfer payload, see `CX::copy`
+pub struct MemToMem<CHANNEL> {
+    pub channel: CHANNEL,
+}
+
+macro_rules! dma {
+    ($($DMAX:ident: ($dmaX:ident, {
+        $($CX:ident: (
+            $x:literal,
+            $cfgrX:ident,
+            $cntrX:ident,
+            $paddrX:ident,
+            $maddrX:ident
+        ),)+
+    }),)+) => {
+        $(
+            pub mod $dmaX {
+                use core::{sync::atomic::{self, Ordering}, ptr, mem, convert::TryFrom};
+
+                use embedded_dma::{ReadBuffer, WriteBuffer};
+
+                use crate::pac::$DMAX;
+
+                use crate::dma::{
+                    CircBuffer, DataSize, DmaExt, Direction, Error, Event, Half, MemToMem, Priority,
+                    Transfer, W, RxDma, TxDma, TransferPayload,
+                };
+                use crate::rcc::{Enable, Rcc};
+
+                #[allow(clippy::manual_non_exhaustive)]
+                pub struct Channels((), $(pub $CX),+);
+
+                // Flags of each channel in INTFR and INTFCR
+                const TCIF: u32 = 0b0010;
+                const HTIF: u32 = 0b0100;
+                const TEIF: u32 = 0b1000;
+
+                $(
+                    /// A singleton that represents a single DMAx channel (channel X in this case)
+                    ///
+                    /// This singleton has exclusive access to the registers of the DMAx channel X
+                    pub struct $CX { _0: () }
+
+                    impl $CX {
+                        /// Position of this channel's flags in INTFR and INTFCR
+                        const FLAGS: u32 = 4 * ($x - 1);
+
+                        /// Associated peripheral `address`
+                        ///
+                        /// `inc` indicates whether the address will be incremented after every byte transfer
+                        pub fn set_peripheral_address(&mut self, address: u32, inc: bool) {
+                            self.dma().$paddrX.write(|w| unsafe { w.bits(address) });
+                            self.dma().$cfgrX.modify(|_, w| w.pinc().bit(inc));
+                        }
+
+                        /// `address` where from/to data will be read/write
+                        ///
+                        /// `inc` indicates whether the address will be incremented after every byte transfer
+                        pub fn set_memory_address(&mut self, address: u32, inc: bool) {
+                            self.dma().$maddrX.write(|w| unsafe { w.bits(address) });
+                            self.dma().$cfgrX.modify(|_, w| w.minc().bit(inc));
+                        }
+
+                        /// Number of data items to transfer
+                        ///
+                        /// # Panics
+                        ///
+                        /// Panics if `len` is above 65535, the width of the counter.
+                        pub fn set_transfer_length(&mut self, len: usize) {
+                            let len = u16::try_from(len).unwrap();
+                            self.dma().$cntrX.write(|w| unsafe { w.bits(len as u32) });
+                        }
+
+                        /// Priority of this channel's requests
+                        pub fn set_priority(&mut self, priority: Priority) {
+                            self.dma().$cfgrX.modify(|_, w| unsafe { w.pl().bits(priority as u8) });
+                        }
+
+                        /// Width of the data items read/written at the peripheral and memory addresses
+                        pub fn set_data_size(&mut self, peripheral: DataSize, memory: DataSize) {
+                            self.dma().$cfgrX.modify(|_, w| unsafe {
+                                w.psize()
+                                    .bits(peripheral as u8)
+                                    .msize()
+                                    .bits(memory as u8)
+                            });
+                        }
+
+                        /// Direction of the transfer
+                        pub fn set_direction(&mut self, direction: Direction) {
+                            self.dma().$cfgrX.modify(|_, w| {
+                                w.dir().bit(direction == Direction::MemoryToPeripheral)
+                            });
+                        }
+
+                        /// Restart from the beginning of the buffer when the transfer completes
+                        pub fn set_circular(&mut self, circular: bool) {
+                            self.dma().$cfgrX.modify(|_, w| w.circ().bit(circular));
+                        }
+
+                        /// Transfer between two memory addresses without waiting for peripheral requests
+                        pub fn set_mem2mem(&mut self, mem2mem: bool) {
+                            self.dma().$cfgrX.modify(|_, w| w.mem2mem().bit(mem2mem));
+                        }
+
+                        /// Starts the DMA transfer, clearing the error of the previous one
+                        pub fn start(&mut self) {
+                            self.clear_flags(TEIF);
+                            self.dma().$cfgrX.modify(|_, w| w.en().set_bit());
+                        }
+
+                        /// Stops the DMA transfer
+                        ///
+                        /// A transfer error stays flagged until the next [`start`](Self::start).
+                        pub fn stop(&mut self) {
+                            // Clearing the global flag would also clear TEIF
+                            self.clear_flags(TCIF | HTIF);
+                            self.dma().$cfgrX.modify(|_, w| w.en().clear_bit());
+                        }
+
+                        /// Returns `true` if there's a transfer in progress
+                        ///
+                        /// A transfer that was stopped by a bus error is no longer in progress.
+                        pub fn in_progress(&self) -> bool {
+                            self.flags() & (TCIF | TEIF) == 0
+                        }
+
+                        /// Returns `true` if the first half of the buffer has been transferred
+                        pub fn is_half_transfer(&self) -> bool {
+                            self.flags() & HTIF != 0
+                        }
+
+                        /// Returns `true` if the whole buffer has been transferred
+                        pub fn is_transfer_complete(&self) -> bool {
+                            self.flags() & TCIF != 0
+                        }
+
+                        /// Returns `true` if a bus error stopped the transfer
+                        pub fn is_transfer_error(&self) -> bool {
+                            self.flags() & TEIF != 0
+                        }
+
+                        /// Clears the flag associated with `event`
+                        pub fn clear_event(&mut self, event: Event) {
+                            self.clear_flags(match event {
+                                Event::HalfTransfer => HTIF,
+                                Event::TransferComplete => TCIF,
+                                Event::TransferError => TEIF,
+                            });
+                        }
+                    }
+
+                    impl $CX {
+                        /// Starts listening for an `event`
+                        ///
+                        /// Note, you will also have to enable the channel interrupt in the PFIC
+                        /// to start receiving events.
+                        pub fn listen(&mut self, event: Event) {
+                            match event {
+                                Event::HalfTransfer => self.dma().$cfgrX.modify(|_, w| w.htie().set_bit()),
+                                Event::TransferComplete => {
+                                    self.dma().$cfgrX.modify(|_, w| w.tcie().set_bit())
+                                }
+                                Event::TransferError => self.dma().$cfgrX.modify(|_, w| w.teie().set_bit()),
+                            }
+                        }
+
+                        /// Stops listening for an `event`
+                        pub fn unlisten(&mut self, event: Event) {
+                            match event {
+                                Event::HalfTransfer => {
+                                    self.dma().$cfgrX.modify(|_, w| w.htie().clear_bit())
+                                },
+                                Event::TransferComplete => {
+                                    self.dma().$cfgrX.modify(|_, w| w.tcie().clear_bit())
+                                }
+                                Event::TransferError => {
+                                    self.dma().$cfgrX.modify(|_, w| w.teie().clear_bit())
+                                }
+                            }
+                        }
+
+                        fn dma(&self) -> &crate::pac::dma1::RegisterBlock {
+                            // NOTE(unsafe) this channel only accesses its own registers and
+                            // uses stateless writes to INTFCR
+                            unsafe { &(*$DMAX::ptr()) }
+                        }
+
+                        /// This channel's flags from INTFR
+                        fn flags(&self) -> u32 {
+                            (self.dma().intfr.read().bits() >> Self::FLAGS) & 0b1111
+                        }
+
+                        fn clear_flags(&self, flags: u32) {
+                            self.dma().intfcr.write(|w| unsafe { w.bits(flags << Self::FLAGS) });
+                        }
+
+                        /// Number of data items that remain to be transferred
+                        pub fn get_ndtr(&self) -> u32 {
+                            self.dma().$cntrX.read().bits()
+                        }
+
+                        /// Copy the contents of `src` into `dst` in memory-to-memory mode
+                        ///
+                        /// The number of items copied is the length of the shorter buffer.
+                        ///
+                        /// # Panics
+                        ///
+                        /// Panics if more than 65535 items would be copied.
+                        pub fn copy<S, D, WORD>(
+                            mut self,
+                            src: S,
+                            mut dst: D,
+                        ) -> Transfer<W, (S, D), MemToMem<$CX>>
+                        where
+                            S: ReadBuffer<Word = WORD>,
+                            D: WriteBuffer<Word = WORD>,
+                        {
+                            // NOTE(unsafe) We own the buffers now and we won't call other `&mut`
+                            // on them until the end of the transfer.
+                            let (src_ptr, src_len) = unsafe { src.read_buffer() };
+                            let (dst_ptr, dst_len) = unsafe { dst.write_buffer() };
+
+                            // Source is the "memory" side, destination the "peripheral" side
+                            self.set_memory_address(src_ptr as u32, true);
+                            self.set_peripheral_address(dst_ptr as u32, true);
+                            self.set_transfer_length(src_len.min(dst_len));
+                            self.set_data_size(DataSize::of::<WORD>(), DataSize::of::<WORD>());
+                            self.set_direction(Direction::MemoryToPeripheral);
+                            self.set_circular(false);
+                            self.set_mem2mem(true);
+
+                            atomic::compiler_fence(Ordering::Release);
+
+                            let mut payload = MemToMem { channel: self };
+                            payload.start();
+
+                            Transfer::w((src, dst), payload)
+                        }
+                    }
+
+                    impl TransferPayload for MemToMem<$CX> {
+                        fn start(&mut self) {
+                            self.channel.start();
+                        }
+
+                        fn stop(&mut self) {
+                            self.channel.stop();
+                            self.channel.set_mem2mem(false);
+                        }
+                    }
+
+                    impl<B, PAYLOAD> CircBuffer<B, RxDma<PAYLOAD, $CX>>
+                    where
+                        RxDma<PAYLOAD, $CX>: TransferPayload,
+                    {
+                        /// Peeks into the readable half of the buffer
+                        pub fn peek<R, F>(&mut self, f: F) -> Result<R, Error>
+                            where
+                            F: FnOnce(&B, Half) -> R,
+                        {
+                            let half_being_read = self.readable_half()?;
+
+                            let buf = match half_being_read {
+                                Half::First => &self.buffer[0],
+                                Half::Second => &self.buffer[1],
+                            };
+
+                            let ret = f(buf, half_being_read);
+
+                            let first_half_is_done = self.payload.channel.is_half_transfer();
+                            let second_half_is_done = self.payload.channel.is_transfer_complete();
+
+                            if (half_being_read == Half::First && second_half_is_done) ||
+                                (half_being_read == Half::Second && first_half_is_done) {
+                                Err(Error::Overrun)
+                            } else {
+                                Ok(ret)
+                            }
+                        }
+
+                        /// Returns the `Half` of the buffer that can be read
+                        pub fn readable_half(&mut self) -> Result<Half, Error> {
+                            let first_half_is_done = self.payload.channel.is_half_transfer();
+                            let second_half_is_done = self.payload.channel.is_transfer_complete();
+
+                            if first_half_is_done && second_half_is_done {
+                                return Err(Error::Overrun);
+                            }
+
+                            let last_read_half = self.readable_half;
+
+                            Ok(match last_read_half {
+                                Half::First => {
+                                    if second_half_is_done {
+                                        self.payload.channel.clear_event(Event::TransferComplete);
+
+                                        self.readable_half = Half::Second;
+                                        Half::Second
+                                    } else {
+                                        last_read_half
+                                    }
+                                }
+                                Half::Second => {
+                                    if first_half_is_done {
+                                        self.payload.channel.clear_event(Event::HalfTransfer);
+
+                                        self.readable_half = Half::First;
+                                        Half::First
+                                    } else {
+                                        last_read_half
+                                    }
+                                }
+                            })
+                        }
+
+                        /// Stops the transfer and returns the underlying buffer and RxDma
+                        pub fn stop(mut self) -> (&'static mut [B; 2], RxDma<PAYLOAD, $CX>) {
+                            self.payload.stop();
+
+                            (self.buffer, self.payload)
+                        }
+                    }
+
+                    transfer_wait!([PAYLOAD], RxDma<PAYLOAD, $CX>);
+                    transfer_wait!([PAYLOAD], TxDma<PAYLOAD, $CX>);
+                    transfer_wait!([], MemToMem<$CX>);
+
+                    impl<BUFFER, PAYLOAD> Transfer<W, BUFFER, RxDma<PAYLOAD, $CX>>
+                    where
+                        RxDma<PAYLOAD, $CX>: TransferPayload,
+                    {
+                        /// Data that has been received so far
+                        pub fn peek<T>(&self) -> &[T]
+                        where
+                            BUFFER: AsRef<[T]>,
+                        {
+                            let pending = self.payload.channel.get_ndtr() as usize;
+
+                            let slice = self.buffer.as_ref();
+                            let capacity = slice.len();
+
+                            &slice[..(capacity - pending)]
+                        }
+                    }
+                )+
+
+                impl DmaExt for $DMAX {
+                    type Channels = Channels;
+
+                    fn split(self, rcc: &mut Rcc) -> Channels {
+                        $DMAX::enable(&mut rcc.ahb);
+
+                        // reset the DMA control registers (stops all on-going transfers)
+                        $(
+                            self.$cfgrX.reset();
+                        )+
+
+                        Channels((), $($CX { _0: () }),+)
+                    }
+                }
+            }
+        )+
+    }
+}
+
+/// `is_done` and `wait` for a transfer whose payload is driven by a single channel
+macro_rules! transfer_wait {
+    ([$($P:ident)?], $PAYLOAD:ty) => {
+        impl<BUFFER, $($P,)? MODE> Transfer<MODE, BUFFER, $PAYLOAD>
+        where
+            $PAYLOAD: TransferPayload,
+        {
+            pub fn is_done(&self) -> bool {
+                !self.payload.channel.in_progress()
+            }
+
+            /// Returns `true` once the first half of the buffer has been transferred
+            pub fn is_half_done(&self) -> bool {
+                self.payload.channel.is_half_transfer()
+            }
+
+            /// Wait for the transfer to end and return its resources
+            ///
+            /// Also returns if a bus error stopped the transfer, which is still reported by
+            /// the channel's `is_transfer_error` afterwards.
+            pub fn wait(mut self) -> (BUFFER, $PAYLOAD) {
+                while !self.is_done() {}
+
+                atomic::compiler_fence(Ordering::Acquire);
+
+                self.payload.stop();
+
+                // we need a read here to make the Acquire fence effective
+                // we do *not* need this if `dma.stop` does a RMW operation
+                unsafe {
+                    ptr::read_volatile(&0);
+                }
+
+                // we need a fence here for the same reason we need one in `Transfer.wait`
+                atomic::compiler_fence(Ordering::Acquire);
+
+                // NOTE(unsafe) There is no panic branch between getting the resources
+                // and forgetting `self`.
+                unsafe {
+                    let buffer = ptr::read(&self.buffer);
+                    let payload = ptr::read(&self.payload);
+                    mem::forget(self);
+                    (buffer, payload)
+                }
+            }
+        }
+    };
+}
+
+dma! {
+    DMA1: (dma1, {
+        C1: (1, cfgr1, cntr1, paddr1, maddr1),
+        C2: (2, cfgr2, cntr2, paddr2, maddr2),
+        C3: (3, cfgr3, cntr3, paddr3, maddr3),
+        C4: (4, cfgr4, cntr4, paddr4, maddr4),
+        C5: (5, cfgr5, cntr5, paddr5, maddr5),
+        C6: (6, cfgr6, cntr6, paddr6, maddr6),
+        C7: (7, cfgr7, cntr7, paddr7, maddr7),
+    }),
+}
+
+/// DMA Receiver
+pub struct RxDma<PAYLOAD, RXCH> {
+    pub payload: PAYLOAD,
+    pub channel: RXCH,
+}
+
+/// DMA Transmitter
+pub struct TxDma<PAYLOAD, TXCH> {
+    pub payload: PAYLOAD,
+    pub channel: TXCH,
+}
+
+pub trait Receive {
+    type RxChannel;
+    type TransmittedWord;
+}
+
+pub trait Transmit {
+    type TxChannel;
+    type ReceivedWord;
+}
+
+/// Trait for circular DMA readings from peripheral to memory.
+pub trait CircReadDma<B, RS>: Receive
+where
+    &'static mut [B; 2]: WriteBuffer<Word = RS>,
+    B: 'static,
+    Self: core::marker::Sized,
+{
+    fn circ_read(self, buffer: &'static mut [B; 2]) -> CircBuffer<B, Self>;
+}
+
+/// Trait for DMA readings from peripheral to memory.
+pub trait ReadDma<B, RS>: Receive
+where
+    B: WriteBuffer<Word = RS>,
+    Self: core::marker::Sized + TransferPayload,
+{
+    fn read(self, buffer: B) -> Transfer<W, B, Self>;
+}
+
+/// Trait for DMA writing from memory to peripheral.
+pub trait WriteDma<B, TS>: Transmit
+where
+    B: ReadBuffer<Word = TS>,
+    Self: core::marker::Sized + TransferPayload,
+{
+    fn write(self, buffer: B) -> Transfer<R, B, Self>;
+}
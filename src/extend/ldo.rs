@@ -0,0 +1,37 @@
+//! Core voltage regulator trim.
+//!
+//! Raising the core voltage gives more margin at high system clocks and low supply
+//! voltages, at the cost of a higher power consumption.
+
+use super::Extend;
+
+/// `LDOTRIM` bit of `EXTEND_CTR`
+const LDOTRIM: u32 = 1 << 10;
+
+/// Output level of the core LDO regulator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LdoTrim {
+    /// Rated voltage, the value on reset
+    Rated,
+    /// Raised voltage
+    Raised,
+}
+
+impl Extend {
+    /// Returns the current LDO trim level
+    pub fn ldo_trim(&self) -> LdoTrim {
+        if self.extend.extend_ctr.read().bits() & LDOTRIM != 0 {
+            LdoTrim::Raised
+        } else {
+            LdoTrim::Rated
+        }
+    }
+
+    /// Set the LDO trim level
+    pub fn set_ldo_trim(&mut self, trim: LdoTrim) {
+        self.modify_ctr(|bits| match trim {
+            LdoTrim::Rated => bits & !LDOTRIM,
+            LdoTrim::Raised => bits | LDOTRIM,
+        });
+    }
+}
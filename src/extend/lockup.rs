@@ -0,0 +1,27 @@
+//! Core lock-up monitor.
+//!
+//! The core locks up when it faults while handling a fault. With the monitor enabled
+//! a lock-up resets the chip, the reset is reported as
+//! [`ResetReason::LockUp`](crate::rcc::ResetReason::LockUp).
+
+use super::Extend;
+
+/// `LKUPEN` bit of `EXTEND_CTR`
+const LKUPEN: u32 = 1 << 6;
+
+impl Extend {
+    /// Reset the chip when the core locks up
+    pub fn enable_lockup_reset(&mut self) {
+        self.modify_ctr(|bits| bits | LKUPEN);
+    }
+
+    /// Let the core hang when it locks up
+    pub fn disable_lockup_reset(&mut self) {
+        self.modify_ctr(|bits| bits & !LKUPEN);
+    }
+
+    /// Returns `true` if a lock-up resets the chip
+    pub fn is_lockup_reset_enabled(&self) -> bool {
+        self.extend.extend_ctr.read().bits() & LKUPEN != 0
+    }
+}
@@ -1,6 +1,51 @@
 //! Extended configuration unit
 //! * [x] OPA - Configure operation amplifier. See [`opa`].
-//! * [ ] LDOTRIM - Adjusting the built-in voltage.
-//! * [ ] LKUPEN - Lock-up function monitoring.
+//! * [x] LDOTRIM - Adjusting the built-in voltage. See [`ldo`].
+//! * [x] LKUPEN - Lock-up function monitoring. See [`lockup`].
+//!
+//! All functions are configured through the shared `EXTEND_CTR` register, which is
+//! owned by [`Extend`].
 
+use crate::pac::EXTEND;
+
+pub mod ldo;
+pub mod lockup;
 pub mod opa;
+
+pub trait ExtendExt {
+    /// Take ownership of the extended configuration unit
+    fn constrain(self) -> Extend;
+}
+
+impl ExtendExt for EXTEND {
+    fn constrain(self) -> Extend {
+        Extend { extend: self }
+    }
+}
+
+/// `LKUPRST` bit of `EXTEND_CTR`, cleared by writing 1
+pub(crate) const LKUPRST: u32 = 1 << 7;
+
+/// Extended configuration unit
+pub struct Extend {
+    extend: EXTEND,
+}
+
+impl Extend {
+    /// Releases the EXTEND peripheral
+    pub fn free(self) -> EXTEND {
+        self.extend
+    }
+
+    /// Modify `EXTEND_CTR` without clearing the lock-up reset flag
+    ///
+    /// The flag is also cleared through [`ResetReason::clear`](crate::rcc::ResetReason::clear),
+    /// so the register is only modified in critical sections.
+    fn modify_ctr(&mut self, f: impl FnOnce(u32) -> u32) {
+        critical_section::with(|_| {
+            self.extend
+                .extend_ctr
+                .modify(|r, w| unsafe { w.bits(f(r.bits() & !LKUPRST)) });
+        });
+    }
+}
@@ -2,9 +2,10 @@
 //!
 //! The OPA peripheral does not have programmable gain - it relies on external feedback resistors/connections.
 
+use super::{Extend, LKUPRST};
 use crate::{
     gpio::{Analog, Input, PA1, PA2, PD0, PD4, PD7},
-    pac, Sealed,
+    Sealed,
 };
 
 /// In-built operational amplifier control.
@@ -26,17 +27,24 @@ impl<MODE: ValidPinMode, P: NonInvertingPin, N: InvertingPin> OpAmp<MODE, P, N>
     /// * `PD0`
     ///
     /// The output of the amplifier is always `PD4`.
-    pub fn enable(non_inverting_pin: P, inverting_pin: N, output_pin: PD4<MODE>) -> Self {
-        unsafe {
-            (*pac::EXTEND::ptr()).extend_ctr.modify(|_, w| {
-                w.opa_en()
+    pub fn enable(
+        extend: &mut Extend,
+        non_inverting_pin: P,
+        inverting_pin: N,
+        output_pin: PD4<MODE>,
+    ) -> Self {
+        critical_section::with(|_| {
+            extend.extend.extend_ctr.modify(|r, w| {
+                // Don't clear the lock-up reset flag
+                unsafe { w.bits(r.bits() & !LKUPRST) }
+                    .opa_en()
                     .set_bit()
                     .opa_psel()
                     .bit(P::OPA_NSEL)
                     .opa_nsel()
                     .bit(N::OPA_PSEL)
             });
-        }
+        });
 
         // We hold on to the pins until the OPA is disabled.
         OpAmp {
@@ -47,18 +55,19 @@ impl<MODE: ValidPinMode, P: NonInvertingPin, N: InvertingPin> OpAmp<MODE, P, N>
     }
 
     /// Turn off the OPA peripheral, returning the pins it was using.
-    pub fn disable(self) -> (P, N, PD4<MODE>) {
-        unsafe {
-            // Clearing all bits back to reset value of 0.
-            (*pac::EXTEND::ptr()).extend_ctr.modify(|_, w| {
-                w.opa_en()
+    pub fn disable(self, extend: &mut Extend) -> (P, N, PD4<MODE>) {
+        // Clearing all bits back to reset value of 0.
+        critical_section::with(|_| {
+            extend.extend.extend_ctr.modify(|r, w| {
+                unsafe { w.bits(r.bits() & !LKUPRST) }
+                    .opa_en()
                     .clear_bit()
                     .opa_psel()
                     .clear_bit()
                     .opa_nsel()
                     .clear_bit()
             });
-        }
+        });
 
         (self.non_inverting_pin, self.inverting_pin, self.output_pin)
     }
@@ -66,7 +75,7 @@ impl<MODE: ValidPinMode, P: NonInvertingPin, N: InvertingPin> OpAmp<MODE, P, N>
 
 /// Pins that can be used as the non-inverting input to the operation amplifier impl this trait.
 pub trait NonInvertingPin: Sealed {
-    /// Value of `OPA_NSEL` bit of [`EXTEND_CTR`](pac::EXTEND) to select this pin.
+    /// Value of `OPA_NSEL` bit of [`EXTEND_CTR`](crate::pac::EXTEND) to select this pin.
     const OPA_NSEL: bool;
 }
 /// `OPP0` - `PA2`
@@ -80,7 +89,7 @@ impl<MODE: ValidPinMode> NonInvertingPin for PD7<MODE> {
 
 /// Pins that can be used as the inverting input to the operation amplifier impl this trait.
 pub trait InvertingPin: Sealed {
-    /// Value of `OPA_PSEL` bit of [`EXTEND_CTR`](pac::EXTEND) to select this pin.
+    /// Value of `OPA_PSEL` bit of [`EXTEND_CTR`](crate::pac::EXTEND) to select this pin.
     const OPA_PSEL: bool;
 }
 /// `OPN0` - `PA1`
@@ -0,0 +1,599 @@
+//! Flash memory
+//!
+//! The flash is organised in 64-byte pages, grouped in 1 KiB blocks. It can be erased
+//! by page with the fast erase, or by block with the standard erase, and is programmed
+//! by half-word, or by page through the fast programming buffer. Offsets used by the
+//! [`NorFlash`] implementation are relative to the start of the flash at `0x0800_0000`.
+//!
+//! The controller is unlocked for each operation and locked again afterwards.
+//!
+//! # Access control
+//!
+//! The number of wait states of flash reads depends on the system clock, it is set by
+//! [`rcc::Config::freeze`](crate::rcc::Config::freeze), see [`Latency`].
+//!
+//! # Option bytes
+//!
+//! [`Flash::read_option_bytes`] decodes the option bytes into an [`OptionBytes`], which
+//! can be modified and written back with [`Flash::write_option_bytes`]. The new options
+//! are loaded on the next system reset.
+//!
+//! # Key-value store
+//!
+//! [`kv::Store`] keeps small values in a few reserved pages, spreading the wear over them.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+use crate::pac::FLASH;
+use crate::signature;
+use crate::time::Hertz;
+
+pub mod kv;
+
+/// Start address of the user flash
+pub const FLASH_START: u32 = 0x0800_0000;
+/// Fast erase and programming page size
+pub const PAGE_SIZE: u32 = 64;
+/// Standard erase block size
+pub const BLOCK_SIZE: u32 = 1024;
+
+pub(crate) const KEY1: u32 = 0x4567_0123;
+pub(crate) const KEY2: u32 = 0xCDEF_89AB;
+
+/// Start address of the option bytes
+const OB_START: u32 = 0x1FFF_F800;
+// Option byte offsets, every byte is stored in a half-word along with its complement
+const OB_RDPR: u32 = 0x0;
+const OB_USER: u32 = 0x2;
+const OB_DATA0: u32 = 0x4;
+const OB_DATA1: u32 = 0x6;
+const OB_WRPR0: u32 = 0x8;
+const OB_WRPR1: u32 = 0xA;
+
+/// RDPR value disabling the read protection
+const RDP_KEY: u8 = 0xA5;
+
+// USER
+const USER_IWDG_SW: u8 = 1 << 0;
+const USER_STANDBY_RST: u8 = 1 << 2;
+const USER_RST_MODE_SHIFT: u8 = 3;
+const USER_RST_MODE_MASK: u8 = 0b11 << USER_RST_MODE_SHIFT;
+const USER_START_MODE: u8 = 1 << 5;
+/// Reserved USER bits, kept in their erased state
+const USER_RESERVED: u8 = 0b1100_0010;
+
+// CTLR
+const CTLR_PG: u32 = 1 << 0;
+const CTLR_PER: u32 = 1 << 1;
+const CTLR_OBPG: u32 = 1 << 4;
+const CTLR_OBER: u32 = 1 << 5;
+const CTLR_STRT: u32 = 1 << 6;
+const CTLR_LOCK: u32 = 1 << 7;
+const CTLR_OPTWRE: u32 = 1 << 9;
+const CTLR_FLOCK: u32 = 1 << 15;
+const CTLR_PAGE_PG: u32 = 1 << 16;
+const CTLR_PAGE_ER: u32 = 1 << 17;
+const CTLR_BUF_LOAD: u32 = 1 << 18;
+const CTLR_BUF_RST: u32 = 1 << 19;
+
+// ACTLR
+const ACTLR_LATENCY_MASK: u32 = 0b11;
+
+// STATR
+const STATR_BSY: u32 = 1 << 0;
+const STATR_WRPRTERR: u32 = 1 << 4;
+const STATR_EOP: u32 = 1 << 5;
+
+/// Flash error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// Offset or length is not aligned to the operation's granularity
+    NotAligned,
+    /// The range is outside of the flash
+    OutOfBounds,
+    /// The range is write protected
+    WriteProtection,
+    /// The programmed data does not read back as written
+    Programming,
+    /// The controller could not be unlocked, the wrong key was written since reset
+    Locked,
+}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::NotAligned => NorFlashErrorKind::NotAligned,
+            Error::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Flash wait states
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Latency {
+    /// No wait state, for a system clock up to 24 MHz
+    Ws0 = 0b00,
+    /// One wait state, for a system clock up to 48 MHz
+    Ws1 = 0b01,
+}
+
+impl Latency {
+    /// Wait states required at the system clock `sysclk`
+    pub const fn for_sysclk(sysclk: Hertz) -> Self {
+        if sysclk.raw() <= 24_000_000 {
+            Latency::Ws0
+        } else {
+            Latency::Ws1
+        }
+    }
+}
+
+/// Returns the current flash latency
+pub fn latency() -> Latency {
+    // NOTE(unsafe) atomic read with no side effects
+    let actlr = unsafe { (*FLASH::ptr()).actlr.read().bits() };
+    match actlr & ACTLR_LATENCY_MASK {
+        0b00 => Latency::Ws0,
+        _ => Latency::Ws1,
+    }
+}
+
+/// Set the flash latency, used while the system clock is switched
+pub(crate) fn set_latency(latency: Latency) {
+    // NOTE(unsafe) ACTLR is only written while the clocks are configured
+    unsafe {
+        (*FLASH::ptr())
+            .actlr
+            .modify(|r, w| w.bits((r.bits() & !ACTLR_LATENCY_MASK) | latency as u32))
+    };
+}
+
+/// Unlock the flash controller with the KEYR key sequence
+pub(crate) fn unlock(flash: &crate::pac::flash::RegisterBlock) {
+    flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+    flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+}
+
+/// Read protection level
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadProtection {
+    /// The flash can be read by the debugger and the bootloader
+    Disabled,
+    /// The flash can only be read by the code running from it
+    ///
+    /// Disabling the read protection again erases the user flash.
+    Enabled,
+}
+
+/// Function of the PD7 pin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetMode {
+    /// PD7 is the nRST input, the reset is held for 128 us
+    Reset128us = 0b00,
+    /// PD7 is the nRST input, the reset is held for 1 ms
+    Reset1ms = 0b01,
+    /// PD7 is the nRST input, the reset is held for 12 ms
+    Reset12ms = 0b10,
+    /// PD7 is a general purpose I/O
+    Gpio = 0b11,
+}
+
+/// Code executed after a power-on reset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartMode {
+    /// Start from the user flash
+    User,
+    /// Start from the bootloader
+    Boot,
+}
+
+/// User configuration option byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UserOptions {
+    /// The independent watchdog is started by hardware after reset
+    pub iwdg_hardware: bool,
+    /// Entering standby mode resets the chip instead
+    pub standby_reset: bool,
+    pub reset_mode: ResetMode,
+    pub start_mode: StartMode,
+}
+
+impl UserOptions {
+    fn from_bits(bits: u8) -> Self {
+        UserOptions {
+            // The bits are cleared to select the hardware watchdog and the standby reset
+            iwdg_hardware: bits & USER_IWDG_SW == 0,
+            standby_reset: bits & USER_STANDBY_RST == 0,
+            reset_mode: match (bits & USER_RST_MODE_MASK) >> USER_RST_MODE_SHIFT {
+                0b00 => ResetMode::Reset128us,
+                0b01 => ResetMode::Reset1ms,
+                0b10 => ResetMode::Reset12ms,
+                _ => ResetMode::Gpio,
+            },
+            start_mode: if bits & USER_START_MODE != 0 {
+                StartMode::Boot
+            } else {
+                StartMode::User
+            },
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        let mut bits = USER_RESERVED | (self.reset_mode as u8) << USER_RST_MODE_SHIFT;
+        if !self.iwdg_hardware {
+            bits |= USER_IWDG_SW;
+        }
+        if !self.standby_reset {
+            bits |= USER_STANDBY_RST;
+        }
+        if self.start_mode == StartMode::Boot {
+            bits |= USER_START_MODE;
+        }
+        bits
+    }
+}
+
+/// Decoded option bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OptionBytes {
+    pub read_protection: ReadProtection,
+    pub user: UserOptions,
+    /// User data bytes `Data0` and `Data1`
+    pub data: [u8; 2],
+    /// Write protected 1 KiB blocks, bit `n` protects block `n`
+    pub write_protection: u16,
+}
+
+impl OptionBytes {
+    /// Read the option bytes from the option byte area
+    ///
+    /// These are the values loaded on the next reset, which may differ from the
+    /// active ones if the option bytes were written since.
+    pub fn read() -> Self {
+        OptionBytes {
+            read_protection: if read_option_byte(OB_RDPR) == RDP_KEY {
+                ReadProtection::Disabled
+            } else {
+                ReadProtection::Enabled
+            },
+            user: UserOptions::from_bits(read_option_byte(OB_USER)),
+            data: [read_option_byte(OB_DATA0), read_option_byte(OB_DATA1)],
+            // The bits are cleared to enable the protection
+            write_protection: !u16::from_le_bytes([
+                read_option_byte(OB_WRPR0),
+                read_option_byte(OB_WRPR1),
+            ]),
+        }
+    }
+
+    /// Option byte values, by offset
+    fn bytes(&self) -> [(u32, u8); 6] {
+        let rdpr = match self.read_protection {
+            ReadProtection::Disabled => RDP_KEY,
+            ReadProtection::Enabled => 0x00,
+        };
+        let [wrpr0, wrpr1] = (!self.write_protection).to_le_bytes();
+        [
+            (OB_RDPR, rdpr),
+            (OB_USER, self.user.bits()),
+            (OB_DATA0, self.data[0]),
+            (OB_DATA1, self.data[1]),
+            (OB_WRPR0, wrpr0),
+            (OB_WRPR1, wrpr1),
+        ]
+    }
+}
+
+fn read_option_byte(offset: u32) -> u8 {
+    // NOTE(unsafe) the option bytes are always readable
+    unsafe { core::ptr::read_volatile((OB_START + offset) as *const u16) as u8 }
+}
+
+/// Flash controller
+pub struct Flash {
+    flash: FLASH,
+}
+
+impl Flash {
+    pub fn new(flash: FLASH) -> Self {
+        Flash { flash }
+    }
+
+    /// Releases the FLASH peripheral
+    pub fn free(self) -> FLASH {
+        self.flash
+    }
+
+    /// Flash size in bytes
+    pub fn size() -> usize {
+        signature::flash_size_kb() as usize * 1024
+    }
+
+    /// Erase the 64-byte page at `address`
+    pub fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        self.check_range(address, PAGE_SIZE, PAGE_SIZE)?;
+        self.unlocked(|flash| flash.erase_at(CTLR_PAGE_ER, address))
+    }
+
+    /// Erase the 1 KiB block at `address`
+    pub fn erase_block(&mut self, address: u32) -> Result<(), Error> {
+        self.check_range(address, BLOCK_SIZE, BLOCK_SIZE)?;
+        self.unlocked(|flash| flash.erase_at(CTLR_PER, address))
+    }
+
+    /// Program the half-word at `address`, which must be erased
+    pub fn program_half_word(&mut self, address: u32, data: u16) -> Result<(), Error> {
+        self.check_range(address, 2, 2)?;
+        self.unlocked(|flash| flash.write_half_word(address, data))
+    }
+
+    /// Program the 64-byte page at `address` through the fast programming buffer
+    ///
+    /// The page must be erased.
+    pub fn program_page(
+        &mut self,
+        address: u32,
+        data: &[u8; PAGE_SIZE as usize],
+    ) -> Result<(), Error> {
+        self.check_range(address, PAGE_SIZE, PAGE_SIZE)?;
+        self.unlocked(|flash| flash.write_page(address, data))
+    }
+
+    /// Read the option bytes, see [`OptionBytes::read`]
+    pub fn read_option_bytes(&self) -> OptionBytes {
+        OptionBytes::read()
+    }
+
+    /// Erase the option bytes and program them with `options`
+    ///
+    /// The options take effect after the next system reset. Option bytes that already
+    /// hold the requested values are left untouched.
+    ///
+    /// The option bytes are blank between the erase and the programming: if the
+    /// chip is reset in between, it starts with the read protection enabled.
+    /// Disabling the read protection erases the user flash, including the running
+    /// program.
+    pub fn write_option_bytes(&mut self, options: &OptionBytes) -> Result<(), Error> {
+        if self.read_option_bytes() == *options {
+            return Ok(());
+        }
+
+        self.unlocked(|flash| {
+            flash.flash.obkeyr.write(|w| unsafe { w.bits(KEY1) });
+            flash.flash.obkeyr.write(|w| unsafe { w.bits(KEY2) });
+            if flash.flash.ctlr.read().bits() & CTLR_OPTWRE == 0 {
+                return Err(Error::Locked);
+            }
+
+            let res = flash.program_option_bytes(options);
+            flash.ctlr_clear(CTLR_OPTWRE);
+            res
+        })
+    }
+
+    /// The controller and the option bytes must be unlocked
+    fn program_option_bytes(&mut self, options: &OptionBytes) -> Result<(), Error> {
+        self.ctlr_set(CTLR_OBER);
+        self.ctlr_set(CTLR_STRT);
+        let res = self.wait();
+        self.ctlr_clear(CTLR_OBER);
+        res?;
+
+        for (offset, value) in options.bytes() {
+            self.ctlr_set(CTLR_OBPG);
+            // The complement in the upper byte is computed by the controller
+            // NOTE(unsafe) write to the unlocked option byte area
+            unsafe { core::ptr::write_volatile((OB_START + offset) as *mut u16, value as u16) };
+            let res = self.wait();
+            self.ctlr_clear(CTLR_OBPG);
+            res?;
+
+            if read_option_byte(offset) != value {
+                return Err(Error::Programming);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `len` bytes at `address` are in the flash and aligned to `align`
+    fn check_range(&self, address: u32, len: u32, align: u32) -> Result<(), Error> {
+        if !address.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(Error::NotAligned);
+        }
+        let end = FLASH_START + Self::size() as u32;
+        if address < FLASH_START || address.checked_add(len).is_none_or(|a| a > end) {
+            return Err(Error::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    fn ctlr_set(&mut self, bits: u32) {
+        self.flash
+            .ctlr
+            .modify(|r, w| unsafe { w.bits(r.bits() | bits) });
+    }
+
+    fn ctlr_clear(&mut self, bits: u32) {
+        self.flash
+            .ctlr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !bits) });
+    }
+
+    /// Run `f` with the controller and the fast mode unlocked, lock both again afterwards
+    fn unlocked<F>(&mut self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Self) -> Result<(), Error>,
+    {
+        unlock(&self.flash);
+        self.flash.modekeyr.write(|w| unsafe { w.bits(KEY1) });
+        self.flash.modekeyr.write(|w| unsafe { w.bits(KEY2) });
+
+        let ctlr = self.flash.ctlr.read().bits();
+        let res = if ctlr & (CTLR_LOCK | CTLR_FLOCK) != 0 {
+            Err(Error::Locked)
+        } else {
+            f(self)
+        };
+
+        self.ctlr_set(CTLR_LOCK | CTLR_FLOCK);
+        res
+    }
+
+    /// Wait for the current operation, then check and clear its status
+    fn wait(&mut self) -> Result<(), Error> {
+        while self.flash.statr.read().bits() & STATR_BSY != 0 {}
+
+        let statr = self.flash.statr.read().bits();
+        // Flags are cleared by writing 1
+        self.flash
+            .statr
+            .write(|w| unsafe { w.bits(statr & (STATR_WRPRTERR | STATR_EOP)) });
+
+        if statr & STATR_WRPRTERR != 0 {
+            Err(Error::WriteProtection)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Erase with `mode` (PAGE_ER or PER), the controller must be unlocked
+    fn erase_at(&mut self, mode: u32, address: u32) -> Result<(), Error> {
+        self.ctlr_set(mode);
+        self.flash.addr.write(|w| unsafe { w.bits(address) });
+        self.ctlr_set(CTLR_STRT);
+        let res = self.wait();
+        self.ctlr_clear(mode);
+        res
+    }
+
+    /// The controller must be unlocked
+    fn write_half_word(&mut self, address: u32, data: u16) -> Result<(), Error> {
+        self.ctlr_set(CTLR_PG);
+        // NOTE(unsafe) the address was checked to be in the flash
+        unsafe { core::ptr::write_volatile(address as *mut u16, data) };
+        let res = self.wait();
+        self.ctlr_clear(CTLR_PG);
+        res?;
+
+        // NOTE(unsafe) the address was checked to be in the flash
+        if unsafe { core::ptr::read_volatile(address as *const u16) } != data {
+            return Err(Error::Programming);
+        }
+        Ok(())
+    }
+
+    /// The controller must be unlocked
+    fn write_page(&mut self, address: u32, data: &[u8; PAGE_SIZE as usize]) -> Result<(), Error> {
+        // Reset the programming buffer
+        self.ctlr_set(CTLR_PAGE_PG);
+        self.ctlr_set(CTLR_BUF_RST);
+        let res = self.wait();
+        self.ctlr_clear(CTLR_PAGE_PG);
+        res?;
+
+        // Load the buffer one word at a time
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            self.ctlr_set(CTLR_PAGE_PG);
+            // NOTE(unsafe) the address was checked to be in the flash
+            unsafe { core::ptr::write_volatile((address + 4 * i as u32) as *mut u32, word) };
+            self.ctlr_set(CTLR_BUF_LOAD);
+            let res = self.wait();
+            self.ctlr_clear(CTLR_PAGE_PG);
+            res?;
+        }
+
+        // Program the page
+        self.ctlr_set(CTLR_PAGE_PG);
+        self.flash.addr.write(|w| unsafe { w.bits(address) });
+        self.ctlr_set(CTLR_STRT);
+        let res = self.wait();
+        self.ctlr_clear(CTLR_PAGE_PG);
+        res?;
+
+        // NOTE(unsafe) the address was checked to be in the flash
+        let written = unsafe { &*(address as *const [u8; PAGE_SIZE as usize]) };
+        if written != data {
+            return Err(Error::Programming);
+        }
+        Ok(())
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let address = FLASH_START.wrapping_add(offset);
+        self.check_range(address, bytes.len() as u32, 1)?;
+
+        // NOTE(unsafe) the range was checked to be in the flash
+        let src = unsafe { core::slice::from_raw_parts(address as *const u8, bytes.len()) };
+        bytes.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        Self::size()
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 2;
+    const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if from > to {
+            return Err(Error::OutOfBounds);
+        }
+        let (from, to) = (FLASH_START.wrapping_add(from), FLASH_START.wrapping_add(to));
+        self.check_range(from, to - from, PAGE_SIZE)?;
+
+        self.unlocked(|flash| {
+            let mut address = from;
+            while address < to {
+                // Erase whole blocks at once where possible
+                if address % BLOCK_SIZE == 0 && to - address >= BLOCK_SIZE {
+                    flash.erase_at(CTLR_PER, address)?;
+                    address += BLOCK_SIZE;
+                } else {
+                    flash.erase_at(CTLR_PAGE_ER, address)?;
+                    address += PAGE_SIZE;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let from = FLASH_START.wrapping_add(offset);
+        self.check_range(from, bytes.len() as u32, 2)?;
+
+        self.unlocked(|flash| {
+            let mut address = from;
+            let mut bytes = bytes;
+            while !bytes.is_empty() {
+                // Use the programming buffer for whole pages
+                if address.is_multiple_of(PAGE_SIZE) && bytes.len() >= PAGE_SIZE as usize {
+                    let (page, rest) = bytes.split_at(PAGE_SIZE as usize);
+                    flash.write_page(address, page.try_into().unwrap())?;
+                    address += PAGE_SIZE;
+                    bytes = rest;
+                } else {
+                    flash.write_half_word(address, u16::from_le_bytes([bytes[0], bytes[1]]))?;
+                    address += 2;
+                    bytes = &bytes[2..];
+                }
+            }
+            Ok(())
+        })
+    }
+}
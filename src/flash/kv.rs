@@ -0,0 +1,643 @@
+//! Key-value store
+//!
+//! A wear-levelled store for small values, keyed by `u16`, on top of any [`NorFlash`].
+//!
+//! The store occupies `PAGES` consecutive pages of `PAGE_SIZE` bytes starting at the
+//! flash offset `START`. One page is active at a time, records are appended to it
+//! until it is full, then the live records are copied to the next page and the store
+//! moves on to it.
+//!
+//! # Layout
+//!
+//! Every page starts with a header holding a magic value and a sequence number, the
+//! active page is the valid one with the highest sequence number. The header is
+//! written after the live records were copied, so a page is only used once it is
+//! complete.
+//!
+//! Records are 4-byte aligned and hold the key, the value length, the value and a
+//! CRC-16 of all of them, which marks the record as complete. A record whose CRC
+//! does not match, e.g. after a power loss during the write, is ignored and the
+//! previous value of its key is returned instead.
+//!
+//! An empty record removes its key, so empty values can not be stored.
+//!
+//! ```ignore
+//! // Two 1 KiB pages at the end of the 16 KiB flash
+//! let mut store = Store::<_, 0x3800, 1024, 2>::new(Flash::new(p.FLASH))?;
+//! store.set(1, &[0x12, 0x34])?;
+//!
+//! let mut buf = [0; MAX_VALUE_LEN];
+//! if let Some(len) = store.get(1, &mut buf)? {
+//!     let value = &buf[..len];
+//! }
+//! ```
+
+use embedded_storage::nor_flash::NorFlash;
+
+/// Largest value that can be stored
+pub const MAX_VALUE_LEN: usize = 32;
+
+/// Records and headers are aligned to this, it must be a multiple of the flash read
+/// and write sizes
+const ALIGN: u32 = 4;
+
+const PAGE_MAGIC: u32 = 0x4B56_5354;
+const PAGE_HEADER_SIZE: u32 = 8;
+
+const RECORD_HEADER_SIZE: usize = 4;
+const CRC_SIZE: usize = 2;
+const MAX_RECORD_SIZE: usize = record_size(MAX_VALUE_LEN) as usize;
+
+/// Key-value store error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    /// Flash driver error
+    Flash(E),
+    /// The value is longer than [`MAX_VALUE_LEN`]
+    ValueTooLarge,
+    /// The buffer is too small for the value
+    BufferTooSmall,
+    /// The live records do not leave room for the new one, even after collecting the
+    /// page
+    Full,
+}
+
+/// Size of a record holding a value of `len` bytes
+const fn record_size(len: usize) -> u32 {
+    let size = (RECORD_HEADER_SIZE + len + CRC_SIZE) as u32;
+    size.div_ceil(ALIGN) * ALIGN
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+enum Entry {
+    /// Erased flash, the end of the records
+    End,
+    /// Record that can not be skipped, nothing follows it
+    Corrupt,
+    /// Incomplete record
+    Invalid {
+        size: u32,
+    },
+    Record {
+        key: u16,
+        len: usize,
+        size: u32,
+    },
+}
+
+/// Key-value store on `PAGES` pages of `PAGE_SIZE` bytes at offset `START` of `F`
+pub struct Store<F, const START: u32, const PAGE_SIZE: u32, const PAGES: usize> {
+    flash: F,
+    /// Active page
+    page: usize,
+    /// Sequence number of the active page
+    seq: u32,
+    /// Offset of the next record in the active page
+    offset: u32,
+}
+
+impl<F, const START: u32, const PAGE_SIZE: u32, const PAGES: usize>
+    Store<F, START, PAGE_SIZE, PAGES>
+where
+    F: NorFlash,
+{
+    /// Open the store, recovering from an interrupted write or page collection
+    ///
+    /// The store is formatted if no page holds a valid header.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pages are not erasable on their own or don't fit in the flash.
+    pub fn new(flash: F) -> Result<Self, Error<F::Error>> {
+        assert!(PAGES >= 2);
+        assert!(PAGE_SIZE >= PAGE_HEADER_SIZE + MAX_RECORD_SIZE as u32);
+        assert!(
+            PAGE_SIZE.is_multiple_of(F::ERASE_SIZE as u32)
+                && START.is_multiple_of(F::ERASE_SIZE as u32)
+        );
+        assert!(
+            ALIGN.is_multiple_of(F::WRITE_SIZE as u32) && ALIGN.is_multiple_of(F::READ_SIZE as u32)
+        );
+        assert!(START as usize + PAGE_SIZE as usize * PAGES <= flash.capacity());
+
+        let mut store = Store {
+            flash,
+            page: 0,
+            seq: 0,
+            offset: PAGE_HEADER_SIZE,
+        };
+
+        let mut active = None;
+        for page in 0..PAGES {
+            if let Some(seq) = store.page_seq(page)? {
+                match active {
+                    // Sequence numbers may wrap around
+                    Some((_, active_seq)) if (seq.wrapping_sub(active_seq) as i32) <= 0 => {}
+                    _ => active = Some((page, seq)),
+                }
+            }
+        }
+
+        match active {
+            Some((page, seq)) => {
+                store.page = page;
+                store.seq = seq;
+                store.offset = store.end_of(page)?;
+            }
+            None => store.format()?,
+        }
+        Ok(store)
+    }
+
+    /// Releases the flash
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    /// Erase the store
+    pub fn format(&mut self) -> Result<(), Error<F::Error>> {
+        self.flash
+            .erase(START, START + PAGE_SIZE * PAGES as u32)
+            .map_err(Error::Flash)?;
+        self.page = 0;
+        self.seq = 0;
+        self.offset = PAGE_HEADER_SIZE;
+        self.write_page_header(0, 0)
+    }
+
+    /// Copy the value of `key` into `buf`
+    ///
+    /// Returns the length of the value, or `None` if the key is not stored.
+    pub fn get(&mut self, key: u16, buf: &mut [u8]) -> Result<Option<usize>, Error<F::Error>> {
+        match self.find(key)? {
+            Some((offset, len)) => {
+                if buf.len() < len {
+                    return Err(Error::BufferTooSmall);
+                }
+                let mut record = [0; MAX_RECORD_SIZE];
+                self.read_record(self.page, offset, &mut record)?;
+                buf[..len].copy_from_slice(&record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len]);
+                Ok(Some(len))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns `true` if `key` is stored
+    pub fn contains(&mut self, key: u16) -> Result<bool, Error<F::Error>> {
+        Ok(self.find(key)?.is_some())
+    }
+
+    /// Store `value` under `key`
+    ///
+    /// Nothing is written if the key already holds the value. Storing an empty value
+    /// removes the key.
+    pub fn set(&mut self, key: u16, value: &[u8]) -> Result<(), Error<F::Error>> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::ValueTooLarge);
+        }
+
+        let mut current = [0; MAX_VALUE_LEN];
+        match self.get(key, &mut current)? {
+            Some(len) if &current[..len] == value => return Ok(()),
+            None if value.is_empty() => return Ok(()),
+            _ => {}
+        }
+
+        let size = record_size(value.len());
+        if self.offset + size > PAGE_SIZE {
+            self.collect()?;
+            if self.offset + size > PAGE_SIZE {
+                return Err(Error::Full);
+            }
+        }
+
+        let mut record = [0xFF; MAX_RECORD_SIZE];
+        let len = value.len();
+        record[0..2].copy_from_slice(&key.to_le_bytes());
+        record[2..4].copy_from_slice(&(len as u16).to_le_bytes());
+        record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len].copy_from_slice(value);
+        let crc = crc16(&record[..RECORD_HEADER_SIZE + len]);
+        record[RECORD_HEADER_SIZE + len..RECORD_HEADER_SIZE + len + CRC_SIZE]
+            .copy_from_slice(&crc.to_le_bytes());
+
+        let address = self.address(self.page, self.offset);
+        // Skip the record even if the write fails, the flash is no longer erased there
+        self.offset += size;
+        self.flash
+            .write(address, &record[..size as usize])
+            .map_err(Error::Flash)
+    }
+
+    /// Remove `key`
+    pub fn remove(&mut self, key: u16) -> Result<(), Error<F::Error>> {
+        self.set(key, &[])
+    }
+
+    fn address(&self, page: usize, offset: u32) -> u32 {
+        START + page as u32 * PAGE_SIZE + offset
+    }
+
+    /// Sequence number of `page`, if it holds a valid header
+    fn page_seq(&mut self, page: usize) -> Result<Option<u32>, Error<F::Error>> {
+        let mut header = [0; PAGE_HEADER_SIZE as usize];
+        self.flash
+            .read(self.address(page, 0), &mut header)
+            .map_err(Error::Flash)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let seq = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Ok((magic == PAGE_MAGIC).then_some(seq))
+    }
+
+    fn write_page_header(&mut self, page: usize, seq: u32) -> Result<(), Error<F::Error>> {
+        let mut header = [0; PAGE_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&seq.to_le_bytes());
+        self.flash
+            .write(self.address(page, 0), &header)
+            .map_err(Error::Flash)
+    }
+
+    /// Read the record at `offset` of `page` into `record`
+    fn read_record(
+        &mut self,
+        page: usize,
+        offset: u32,
+        record: &mut [u8; MAX_RECORD_SIZE],
+    ) -> Result<Entry, Error<F::Error>> {
+        if offset + RECORD_HEADER_SIZE as u32 > PAGE_SIZE {
+            return Ok(Entry::End);
+        }
+
+        let address = self.address(page, offset);
+        self.flash
+            .read(address, &mut record[..RECORD_HEADER_SIZE])
+            .map_err(Error::Flash)?;
+        if record[..RECORD_HEADER_SIZE].iter().all(|b| *b == 0xFF) {
+            return Ok(Entry::End);
+        }
+
+        let key = u16::from_le_bytes([record[0], record[1]]);
+        let len = u16::from_le_bytes([record[2], record[3]]) as usize;
+        // An incomplete header, the size of the record is unknown
+        if len > MAX_VALUE_LEN || offset + record_size(len) > PAGE_SIZE {
+            return Ok(Entry::Corrupt);
+        }
+
+        let size = record_size(len);
+        self.flash
+            .read(address, &mut record[..size as usize])
+            .map_err(Error::Flash)?;
+        let crc = u16::from_le_bytes([
+            record[RECORD_HEADER_SIZE + len],
+            record[RECORD_HEADER_SIZE + len + 1],
+        ]);
+        if crc16(&record[..RECORD_HEADER_SIZE + len]) != crc {
+            return Ok(Entry::Invalid { size });
+        }
+        Ok(Entry::Record { key, len, size })
+    }
+
+    /// Offset following the last record of `page`
+    fn end_of(&mut self, page: usize) -> Result<u32, Error<F::Error>> {
+        let mut record = [0; MAX_RECORD_SIZE];
+        let mut offset = PAGE_HEADER_SIZE;
+        loop {
+            match self.read_record(page, offset, &mut record)? {
+                Entry::End => return Ok(offset),
+                // Nothing can be appended after it, the page has to be collected
+                Entry::Corrupt => return Ok(PAGE_SIZE),
+                Entry::Invalid { size } | Entry::Record { size, .. } => offset += size,
+            }
+        }
+    }
+
+    /// Offset and length of the live record of `key` in the active page
+    fn find(&mut self, key: u16) -> Result<Option<(u32, usize)>, Error<F::Error>> {
+        let found = self.last_record(self.page, PAGE_HEADER_SIZE, key)?;
+        // An empty record removes the key
+        Ok(found.filter(|(_, len)| *len != 0))
+    }
+
+    /// Offset and length of the last record of `key` in `page`, starting at `offset`
+    fn last_record(
+        &mut self,
+        page: usize,
+        mut offset: u32,
+        key: u16,
+    ) -> Result<Option<(u32, usize)>, Error<F::Error>> {
+        let mut record = [0; MAX_RECORD_SIZE];
+        let mut found = None;
+        loop {
+            match self.read_record(page, offset, &mut record)? {
+                Entry::End | Entry::Corrupt => return Ok(found),
+                Entry::Invalid { size } => offset += size,
+                Entry::Record { key: k, len, size } => {
+                    if k == key {
+                        found = Some((offset, len));
+                    }
+                    offset += size;
+                }
+            }
+        }
+    }
+
+    /// Copy the live records to the next page and make it the active one
+    fn collect(&mut self) -> Result<(), Error<F::Error>> {
+        let old = self.page;
+        let new = (old + 1) % PAGES;
+        let start = self.address(new, 0);
+        self.flash
+            .erase(start, start + PAGE_SIZE)
+            .map_err(Error::Flash)?;
+
+        let mut record = [0; MAX_RECORD_SIZE];
+        let mut src = PAGE_HEADER_SIZE;
+        let mut dst = PAGE_HEADER_SIZE;
+        loop {
+            match self.read_record(old, src, &mut record)? {
+                Entry::End | Entry::Corrupt => break,
+                Entry::Invalid { size } => src += size,
+                Entry::Record { key, len, size } => {
+                    // Only copy the last record of every key, unless it removes the key
+                    let live = len != 0 && self.last_record(old, src + size, key)?.is_none();
+                    if live {
+                        self.flash
+                            .write(self.address(new, dst), &record[..size as usize])
+                            .map_err(Error::Flash)?;
+                        dst += size;
+                    }
+                    src += size;
+                }
+            }
+        }
+
+        // The new page becomes valid once its header is written
+        let seq = self.seq.wrapping_add(1);
+        self.write_page_header(new, seq)?;
+        self.page = new;
+        self.seq = seq;
+        self.offset = dst;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    use super::*;
+
+    const FLASH_SIZE: usize = 512;
+
+    /// Two 128 byte pages after an unrelated one
+    type TestStore = Store<MockFlash, 128, 128, 2>;
+
+    /// In-memory NOR flash, writes can only clear bits
+    struct MockFlash {
+        data: [u8; FLASH_SIZE],
+        /// Number of bytes written before a simulated power loss, `None` for no limit
+        budget: Option<usize>,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            MockFlash {
+                data: [0xFF; FLASH_SIZE],
+                budget: None,
+            }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let start = offset as usize;
+            let data = self
+                .data
+                .get(start..start + bytes.len())
+                .ok_or(NorFlashErrorKind::OutOfBounds)?;
+            bytes.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            FLASH_SIZE
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 64;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            if !from.is_multiple_of(Self::ERASE_SIZE as u32)
+                || !to.is_multiple_of(Self::ERASE_SIZE as u32)
+            {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            self.data
+                .get_mut(from as usize..to as usize)
+                .ok_or(NorFlashErrorKind::OutOfBounds)?
+                .fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            if !offset.is_multiple_of(Self::WRITE_SIZE as u32)
+                || !bytes.len().is_multiple_of(Self::WRITE_SIZE)
+            {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            let start = offset as usize;
+            let data = self
+                .data
+                .get_mut(start..start + bytes.len())
+                .ok_or(NorFlashErrorKind::OutOfBounds)?;
+            for (cell, byte) in data.iter_mut().zip(bytes) {
+                match &mut self.budget {
+                    Some(0) => return Err(NorFlashErrorKind::Other),
+                    Some(budget) => *budget -= 1,
+                    None => {}
+                }
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn get(store: &mut TestStore, key: u16) -> Option<([u8; MAX_VALUE_LEN], usize)> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let len = store.get(key, &mut buf).unwrap()?;
+        Some((buf, len))
+    }
+
+    fn assert_value(store: &mut TestStore, key: u16, value: &[u8]) {
+        let (buf, len) = get(store, key).expect("key not stored");
+        assert_eq!(&buf[..len], value);
+    }
+
+    fn reopen(store: TestStore) -> TestStore {
+        let mut flash = store.free();
+        flash.budget = None;
+        TestStore::new(flash).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut store = TestStore::new(MockFlash::new()).unwrap();
+        assert!(get(&mut store, 1).is_none());
+
+        store.set(1, &[0x12, 0x34]).unwrap();
+        store.set(2, &[0xAB; MAX_VALUE_LEN]).unwrap();
+        assert_value(&mut store, 1, &[0x12, 0x34]);
+        assert_value(&mut store, 2, &[0xAB; MAX_VALUE_LEN]);
+
+        let mut store = reopen(store);
+        assert_value(&mut store, 1, &[0x12, 0x34]);
+        assert_value(&mut store, 2, &[0xAB; MAX_VALUE_LEN]);
+        assert_eq!(
+            store.set(3, &[0; MAX_VALUE_LEN + 1]),
+            Err(Error::ValueTooLarge)
+        );
+        assert_eq!(store.get(2, &mut [0; 4]), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn overwrite() {
+        let mut store = TestStore::new(MockFlash::new()).unwrap();
+        store.set(1, &[1]).unwrap();
+        store.set(1, &[2, 3]).unwrap();
+        assert_value(&mut store, 1, &[2, 3]);
+
+        // Storing the same value again writes nothing
+        let offset = store.offset;
+        store.set(1, &[2, 3]).unwrap();
+        assert_eq!(store.offset, offset);
+
+        store.remove(1).unwrap();
+        assert!(!store.contains(1).unwrap());
+
+        let mut store = reopen(store);
+        assert!(!store.contains(1).unwrap());
+        store.set(1, &[4]).unwrap();
+        assert_value(&mut store, 1, &[4]);
+    }
+
+    #[test]
+    fn collect_full_page() {
+        let mut store = TestStore::new(MockFlash::new()).unwrap();
+        store.set(1, &[0xAA]).unwrap();
+        store.set(2, &[0xBB]).unwrap();
+        store.remove(2).unwrap();
+
+        // 8 byte records, the 128 byte page holds 15 of them after the header
+        for i in 0..20_u8 {
+            store.set(3, &[i]).unwrap();
+        }
+        assert_eq!(store.page, 1);
+        assert_eq!(store.seq, 1);
+        // The 13th value of key 3 did not fit, only the live records of keys 1 and 3
+        // were copied before it and the rest was appended
+        assert_eq!(store.offset, PAGE_HEADER_SIZE + 10 * record_size(1));
+
+        assert_value(&mut store, 1, &[0xAA]);
+        assert!(!store.contains(2).unwrap());
+        assert_value(&mut store, 3, &[19]);
+
+        let mut store = reopen(store);
+        assert_eq!(store.page, 1);
+        assert_value(&mut store, 1, &[0xAA]);
+        assert!(!store.contains(2).unwrap());
+        assert_value(&mut store, 3, &[19]);
+    }
+
+    #[test]
+    fn full() {
+        let mut store = TestStore::new(MockFlash::new()).unwrap();
+        // Three maximum size records fill a page, the fourth does not fit
+        for key in 0..3 {
+            store.set(key, &[key as u8; MAX_VALUE_LEN]).unwrap();
+        }
+        assert_eq!(store.set(3, &[3; MAX_VALUE_LEN]), Err(Error::Full));
+        for key in 0..3 {
+            assert_value(&mut store, key, &[key as u8; MAX_VALUE_LEN]);
+        }
+    }
+
+    #[test]
+    fn sequence_wraparound() {
+        // A page with the highest sequence number
+        let mut flash = MockFlash::new();
+        let mut header = [0; PAGE_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        flash.write(128, &header).unwrap();
+
+        let mut store = TestStore::new(flash).unwrap();
+        assert_eq!((store.page, store.seq), (0, u32::MAX));
+        for i in 0..20_u8 {
+            store.set(1, &[i]).unwrap();
+        }
+        assert_eq!((store.page, store.seq), (1, 0));
+
+        // Page 1 holds sequence number 0, it is still newer than page 0
+        let mut store = reopen(store);
+        assert_eq!((store.page, store.seq), (1, 0));
+        assert_value(&mut store, 1, &[19]);
+    }
+
+    #[test]
+    fn torn_write() {
+        let mut store = TestStore::new(MockFlash::new()).unwrap();
+        store.set(1, &[1, 2, 3, 4]).unwrap();
+
+        // Power loss after the record header, the rest of the record stays erased
+        store.flash.budget = Some(RECORD_HEADER_SIZE + 2);
+        assert!(matches!(store.set(1, &[5, 6, 7, 8]), Err(Error::Flash(_))));
+
+        let mut store = reopen(store);
+        assert_value(&mut store, 1, &[1, 2, 3, 4]);
+        assert_eq!(store.offset, PAGE_HEADER_SIZE + 2 * record_size(4));
+
+        // Records after the torn one are found
+        store.set(1, &[9]).unwrap();
+        let mut store = reopen(store);
+        assert_value(&mut store, 1, &[9]);
+    }
+
+    #[test]
+    fn bad_crc() {
+        let mut store = TestStore::new(MockFlash::new()).unwrap();
+        store.set(1, &[1]).unwrap();
+        store.set(1, &[2]).unwrap();
+
+        // Clear bits in the value of the second record
+        let address = store.address(0, PAGE_HEADER_SIZE + record_size(1));
+        let mut flash = store.free();
+        flash.data[address as usize + RECORD_HEADER_SIZE] = 0;
+
+        let mut store = TestStore::new(flash).unwrap();
+        assert_value(&mut store, 1, &[1]);
+    }
+}
@@ -0,0 +1,91 @@
+use super::{Edge, Interruptable, PinExt};
+use crate::pac::{AFIO, EXTI};
+use crate::rcc::{Enable, Rcc};
+
+/// External Interrupt Pin
+pub trait ExtiPin {
+    /// Connect the pin's EXTI line to its port
+    ///
+    /// Every line can only be connected to one port at a time, e.g. PC3 and PD3 share line 3.
+    fn make_interrupt_source(&mut self, afio: &mut AFIO, rcc: &mut Rcc);
+    /// Select the edges that trigger the line
+    fn trigger_on_edge(&mut self, exti: &mut EXTI, edge: Edge);
+    /// Unmask the line's interrupt
+    fn enable_interrupt(&mut self, exti: &mut EXTI);
+    /// Mask the line's interrupt
+    fn disable_interrupt(&mut self, exti: &mut EXTI);
+    /// Raise an event on the line, which wakes the core up from WFE
+    fn enable_event(&mut self, exti: &mut EXTI);
+    /// Stop raising events on the line
+    fn disable_event(&mut self, exti: &mut EXTI);
+    /// Clear the line's pending flag
+    fn clear_interrupt_pending_bit(&mut self);
+    /// Returns `true` if the line's pending flag is set
+    fn check_interrupt(&self) -> bool;
+}
+
+impl<PIN> ExtiPin for PIN
+where
+    PIN: PinExt,
+    PIN::Mode: Interruptable,
+{
+    fn make_interrupt_source(&mut self, afio: &mut AFIO, rcc: &mut Rcc) {
+        AFIO::enable(&mut rcc.apb2);
+
+        // 2 bits per line, the port id is the value to select: 00 PA, 10 PC, 11 PD
+        let offset = 2 * self.pin_id();
+        let port = self.port_id() as u32;
+        afio.exticr
+            .modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << offset)) | (port << offset)) });
+    }
+
+    fn trigger_on_edge(&mut self, exti: &mut EXTI, edge: Edge) {
+        let mask = 1 << self.pin_id();
+        match edge {
+            Edge::Rising => {
+                exti.rtenr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+                exti.ftenr
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+            }
+            Edge::Falling => {
+                exti.ftenr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+                exti.rtenr
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+            }
+            Edge::Both => {
+                exti.rtenr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+                exti.ftenr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+            }
+        }
+    }
+
+    fn enable_interrupt(&mut self, exti: &mut EXTI) {
+        exti.intenr
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << self.pin_id())) });
+    }
+
+    fn disable_interrupt(&mut self, exti: &mut EXTI) {
+        exti.intenr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << self.pin_id())) });
+    }
+
+    fn enable_event(&mut self, exti: &mut EXTI) {
+        exti.evenr
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << self.pin_id())) });
+    }
+
+    fn disable_event(&mut self, exti: &mut EXTI) {
+        exti.evenr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << self.pin_id())) });
+    }
+
+    fn clear_interrupt_pending_bit(&mut self) {
+        // NOTE(unsafe) write 1 to clear, other lines are not affected
+        unsafe { (*EXTI::ptr()).intfr.write(|w| w.bits(1 << self.pin_id())) };
+    }
+
+    fn check_interrupt(&self) -> bool {
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { ((*EXTI::ptr()).intfr.read().bits() & (1 << self.pin_id())) != 0 }
+    }
+}
@@ -25,12 +25,14 @@ impl<const P: char, const N: u8, MODE> ErrorType for Pin<P, N, Output<MODE>> {
 impl<const P: char, const N: u8, MODE> OutputPin for Pin<P, N, Output<MODE>> {
     #[inline]
     fn set_high(&mut self) -> Result<(), Self::Error> {
-        Ok(self.set_high())
+        self.set_high();
+        Ok(())
     }
 
     #[inline]
     fn set_low(&mut self) -> Result<(), Self::Error> {
-        Ok(self.set_low())
+        self.set_low();
+        Ok(())
     }
 }
 
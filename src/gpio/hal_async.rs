@@ -0,0 +1,177 @@
+//! `embedded-hal-async` digital traits, backed by the EXTI lines
+//!
+//! Waiting on a pin takes over its EXTI line: the port is connected in AFIO, the
+//! requested edges are configured and the line's interrupt is unmasked until the edge
+//! arrives. The EXTI7_0 interrupt has to be enabled and must call [`on_exti_interrupt`]:
+//!
+//! ```ignore
+//! #[qingke_rt::interrupt]
+//! fn EXTI7_0() {
+//!     hal::gpio::on_exti_interrupt();
+//! }
+//! ```
+//!
+//! Do not use the same line through [`ExtiPin`](super::ExtiPin) at the same time.
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use critical_section::Mutex;
+use embedded_hal_async::digital::Wait;
+
+use super::{Input, Pin};
+use crate::pac::{AFIO, EXTI};
+use crate::rcc::Enable;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+static WAKERS: [Mutex<RefCell<Option<Waker>>>; 8] = [NO_WAKER; 8];
+/// Lines unmasked by a pending wait, only modified in critical sections
+static ARMED: AtomicU8 = AtomicU8::new(0);
+
+/// Wake the tasks waiting on the EXTI lines that triggered
+///
+/// Call this from the EXTI7_0 interrupt handler. Lines are masked again once they
+/// triggered, lines that are not waited on are left untouched.
+pub fn on_exti_interrupt() {
+    // NOTE(unsafe) only the lines that are unmasked by a pending wait are touched
+    let exti = unsafe { &*EXTI::ptr() };
+
+    critical_section::with(|cs| {
+        let armed = ARMED.load(Ordering::Relaxed);
+        let pending = exti.intfr.read().bits() & exti.intenr.read().bits() & armed as u32;
+        ARMED.store(armed & !(pending as u8), Ordering::Relaxed);
+        exti.intenr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !pending) });
+        exti.intfr.write(|w| unsafe { w.bits(pending) });
+
+        for (line, waker) in WAKERS.iter().enumerate() {
+            if pending & (1 << line) != 0 {
+                if let Some(waker) = waker.borrow_ref_mut(cs).take() {
+                    waker.wake();
+                }
+            }
+        }
+    });
+}
+
+/// Resolves once the line has been disarmed by [`on_exti_interrupt`]
+struct ExtiInputFuture {
+    line: u8,
+}
+
+impl ExtiInputFuture {
+    fn new(port: u8, line: u8, rising: bool, falling: bool) -> Self {
+        critical_section::with(|_| {
+            // NOTE(unsafe) the line's configuration bits are owned by the pin
+            let afio = unsafe { &*AFIO::ptr() };
+            let exti = unsafe { &*EXTI::ptr() };
+            let mask = 1 << line;
+
+            unsafe { AFIO::enable_unchecked() };
+            let offset = 2 * line;
+            afio.exticr.modify(|r, w| unsafe {
+                w.bits((r.bits() & !(0b11 << offset)) | ((port as u32) << offset))
+            });
+
+            exti.rtenr.modify(|r, w| unsafe {
+                w.bits(if rising {
+                    r.bits() | mask
+                } else {
+                    r.bits() & !mask
+                })
+            });
+            exti.ftenr.modify(|r, w| unsafe {
+                w.bits(if falling {
+                    r.bits() | mask
+                } else {
+                    r.bits() & !mask
+                })
+            });
+            exti.intfr.write(|w| unsafe { w.bits(mask) });
+            exti.intenr
+                .modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+            ARMED.store(ARMED.load(Ordering::Relaxed) | mask as u8, Ordering::Relaxed);
+        });
+
+        Self { line }
+    }
+}
+
+impl Future for ExtiInputFuture {
+    type Output = ();
+
+    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let armed = critical_section::with(|cs| {
+            WAKERS[self.line as usize]
+                .borrow_ref_mut(cs)
+                .replace(cx.waker().clone());
+            ARMED.load(Ordering::Relaxed) & (1 << self.line) != 0
+        });
+
+        if armed {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+impl Drop for ExtiInputFuture {
+    fn drop(&mut self) {
+        critical_section::with(|cs| {
+            let mask = 1 << self.line;
+            let armed = ARMED.load(Ordering::Relaxed);
+            if armed & mask != 0 {
+                // NOTE(unsafe) the line is still unmasked by this wait
+                let exti = unsafe { &*EXTI::ptr() };
+                exti.intenr
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !(mask as u32)) });
+                ARMED.store(armed & !mask, Ordering::Relaxed);
+            }
+            WAKERS[self.line as usize].borrow_ref_mut(cs).take();
+        });
+    }
+}
+
+impl<const P: char, const N: u8, MODE> Pin<P, N, Input<MODE>> {
+    fn wait_for_edge(&mut self, rising: bool, falling: bool) -> ExtiInputFuture {
+        ExtiInputFuture::new(P as u8 - b'A', N, rising, falling)
+    }
+}
+
+impl<const P: char, const N: u8, MODE> Wait for Pin<P, N, Input<MODE>> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        // Arm the line before checking the level, so an edge in between is not missed
+        let edge = self.wait_for_edge(true, false);
+        if !self.is_high() {
+            edge.await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        let edge = self.wait_for_edge(false, true);
+        if !self.is_low() {
+            edge.await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(true, false).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(false, true).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(true, true).await;
+        Ok(())
+    }
+}
@@ -6,9 +6,13 @@ use core::marker::PhantomData;
 pub use embedded_hal_02::digital::v2::PinState;
 
 mod convert;
+mod exti;
 mod hal_02;
 mod hal_1;
+mod hal_async;
 mod partially_erased;
+pub use exti::ExtiPin;
+pub use hal_async::on_exti_interrupt;
 pub use partially_erased::{PEPin, PartiallyErasedPin};
 
 /// A filler pin type
@@ -80,8 +84,18 @@ pub trait OutputSpeed<CR> {
     fn set_speed(&mut self, cr: &mut CR, speed: Speed);
 }
 
-// TODO: interrupts
-// Edge, Interruptable
+/// Signal edge that triggers an external interrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// Marker for the pin modes that can be used as an external interrupt source
+pub trait Interruptable {}
+
+impl<MODE> Interruptable for Input<MODE> {}
 
 /// Generic pin type
 ///
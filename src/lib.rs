@@ -17,23 +17,27 @@ pub use self::peripherals::Peripherals;
 pub use ch32v0::ch32v003 as pac;
 
 #[cfg(feature = "rt")]
+#[allow(unused_imports)]
 use pac::__EXTERNAL_INTERRUPTS as _;
 
 pub mod adc;
 pub mod gpio;
 pub mod pwr;
+pub mod pfic;
 pub mod rcc;
-//
-// pub mod pfic;
 pub mod delay;
+pub mod dma;
 pub mod extend;
+pub mod flash;
 pub mod i2c;
 pub mod serial;
 pub mod signature;
+pub mod spi;
 pub mod time;
 pub mod timer;
 pub mod watchdog;
 
+#[cfg(target_arch = "riscv32")]
 mod critical_section;
 pub mod debug;
 mod peripheral;
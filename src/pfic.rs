@@ -1,9 +1,73 @@
 //! Programmable Fast Interrupt Controller (PFIC)
+//!
+//! The PFIC registers are accessed by offset from the peripheral base, every
+//! interrupt has one bit in the 32-bit wide enable, pending and active registers
+//! and one byte in the priority registers, indexed by its interrupt number.
+//!
+//! Priorities are 8-bit values where lower values have higher priority. The
+//! CH32V003 only implements the two upper bits: with nesting enabled, bit 7 selects
+//! the preemption level and bit 6 the sub-priority.
+//!
+//! Up to two interrupts can be assigned to vector-table-free (VTF) slots, see
+//! [`Pfic::set_vtf`]. Their handler is entered directly instead of through the
+//! vector table, which shortens the interrupt latency.
 
-use crate::{
-    pac::{rcc, AFIO, PFIC},
-    rcc::Enable,
-};
+use crate::pac::{Interrupt, PFIC};
+
+/// Interrupt enable status registers
+const ISR: usize = 0x000;
+/// Interrupt pending status registers
+const IPR: usize = 0x020;
+/// Interrupt priority threshold register
+const ITHRESDR: usize = 0x040;
+/// Interrupt configuration register
+const CFGR: usize = 0x048;
+/// VTF interrupt numbers, one byte per slot
+const VTFIDR: usize = 0x050;
+/// VTF handler addresses, one register per slot
+const VTFADDRR: usize = 0x060;
+/// Interrupt enable set registers
+const IENR: usize = 0x100;
+/// Interrupt enable clear registers
+const IRER: usize = 0x180;
+/// Interrupt pending set registers
+const IPSR: usize = 0x200;
+/// Interrupt pending clear registers
+const IPRR: usize = 0x280;
+/// Interrupt active status registers
+const IACTR: usize = 0x300;
+/// Interrupt priority registers, one byte per interrupt
+const IPRIOR: usize = 0x400;
+
+/// Key that has to accompany a write to CFGR
+const CFGR_KEY3: u32 = 0xBEEF << 16;
+/// Request a system reset
+const CFGR_RESETSYS: u32 = 1 << 7;
+
+/// VTF slot enable bit of VTFADDRR, handlers are always 2-byte aligned
+const VTFADDRR_EN: u32 = 1 << 0;
+
+/// Interrupt nesting enable bit of the INTSYSCR CSR
+const INTSYSCR_INESTEN: usize = 1 << 1;
+
+#[inline(always)]
+fn reg(offset: usize) -> *mut u32 {
+    (PFIC::ptr() as usize + offset) as *mut u32
+}
+
+/// Address of the bitmap register holding `interrupt` and its mask
+#[inline(always)]
+fn bit(base: usize, interrupt: Interrupt) -> (*mut u32, u32) {
+    let nr = interrupt as usize;
+    (reg(base + 4 * (nr / 32)), 1 << (nr % 32))
+}
+
+/// Vector-table-free interrupt slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VtfSlot {
+    Slot0 = 0,
+    Slot1 = 1,
+}
 
 pub trait PficExt {
     fn constrain(self) -> Pfic;
@@ -18,3 +82,191 @@ impl PficExt for PFIC {
 pub struct Pfic {
     pfic: PFIC,
 }
+
+impl Pfic {
+    /// Enables `interrupt`
+    ///
+    /// # Safety
+    ///
+    /// This can break mask-based critical sections
+    #[inline]
+    pub unsafe fn enable(interrupt: Interrupt) {
+        let (reg, mask) = bit(IENR, interrupt);
+        core::ptr::write_volatile(reg, mask);
+    }
+
+    /// Disables `interrupt`
+    #[inline]
+    pub fn disable(interrupt: Interrupt) {
+        let (reg, mask) = bit(IRER, interrupt);
+        // NOTE(unsafe) atomic write to a stateless register
+        unsafe { core::ptr::write_volatile(reg, mask) };
+    }
+
+    /// Returns `true` if `interrupt` is enabled
+    #[inline]
+    pub fn is_enabled(interrupt: Interrupt) -> bool {
+        let (reg, mask) = bit(ISR, interrupt);
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { core::ptr::read_volatile(reg) & mask != 0 }
+    }
+
+    /// Forces `interrupt` into pending state
+    #[inline]
+    pub fn pend(interrupt: Interrupt) {
+        let (reg, mask) = bit(IPSR, interrupt);
+        // NOTE(unsafe) atomic write to a stateless register
+        unsafe { core::ptr::write_volatile(reg, mask) };
+    }
+
+    /// Clears `interrupt`'s pending state
+    #[inline]
+    pub fn unpend(interrupt: Interrupt) {
+        let (reg, mask) = bit(IPRR, interrupt);
+        // NOTE(unsafe) atomic write to a stateless register
+        unsafe { core::ptr::write_volatile(reg, mask) };
+    }
+
+    /// Returns `true` if `interrupt` is pending
+    #[inline]
+    pub fn is_pending(interrupt: Interrupt) -> bool {
+        let (reg, mask) = bit(IPR, interrupt);
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { core::ptr::read_volatile(reg) & mask != 0 }
+    }
+
+    /// Returns `true` if `interrupt` is being serviced, or preempted by a higher priority one
+    #[inline]
+    pub fn is_active(interrupt: Interrupt) -> bool {
+        let (reg, mask) = bit(IACTR, interrupt);
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { core::ptr::read_volatile(reg) & mask != 0 }
+    }
+
+    /// Returns the priority of `interrupt`
+    #[inline]
+    pub fn get_priority(interrupt: Interrupt) -> u8 {
+        let reg = reg(IPRIOR + interrupt as usize) as *mut u8;
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { core::ptr::read_volatile(reg) }
+    }
+
+    /// Sets the priority of `interrupt` to `priority`
+    ///
+    /// # Safety
+    ///
+    /// Changing priority levels can break priority-based critical sections
+    #[inline]
+    pub unsafe fn set_priority(&mut self, interrupt: Interrupt, priority: u8) {
+        let reg = reg(IPRIOR + interrupt as usize) as *mut u8;
+        core::ptr::write_volatile(reg, priority);
+    }
+
+    /// Returns the priority threshold
+    #[inline]
+    pub fn threshold() -> u8 {
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { core::ptr::read_volatile(reg(ITHRESDR)) as u8 }
+    }
+
+    /// Only interrupts with a priority value lower than `threshold` are serviced
+    ///
+    /// A threshold of 0 disables the threshold.
+    ///
+    /// # Safety
+    ///
+    /// Raising the threshold can break priority-based critical sections
+    #[inline]
+    pub unsafe fn set_threshold(&mut self, threshold: u8) {
+        core::ptr::write_volatile(reg(ITHRESDR), threshold as u32);
+    }
+
+    /// Allow higher priority interrupts to preempt running handlers
+    ///
+    /// Nesting is enabled after reset. Disabling it makes handlers run to completion.
+    #[inline]
+    pub fn set_nesting(&mut self, enabled: bool) {
+        // NOTE(unsafe) INTSYSCR is only written through `&mut Pfic`
+        unsafe {
+            if enabled {
+                core::arch::asm!("csrs 0x804, {0}", in(reg) INTSYSCR_INESTEN);
+            } else {
+                core::arch::asm!("csrc 0x804, {0}", in(reg) INTSYSCR_INESTEN);
+            }
+        }
+    }
+
+    /// Returns `true` if interrupt nesting is enabled
+    #[inline]
+    pub fn is_nesting_enabled() -> bool {
+        let intsyscr: usize;
+        // NOTE(unsafe) read of a CSR with no side effects
+        unsafe { core::arch::asm!("csrr {0}, 0x804", out(reg) intsyscr) };
+        intsyscr & INTSYSCR_INESTEN != 0
+    }
+
+    /// Assign `interrupt` to a VTF slot and enable it
+    ///
+    /// The CPU jumps straight to `handler` when `interrupt` is taken. Interrupts in VTF
+    /// slots are still masked by the global interrupt enable, so critical sections keep
+    /// working.
+    ///
+    /// # Safety
+    ///
+    /// `handler` is entered directly from the interrupt, it must be an interrupt handler:
+    /// save the registers it uses beyond the ones stacked by the hardware, and return
+    /// with `mret`.
+    pub unsafe fn set_vtf(
+        &mut self,
+        slot: VtfSlot,
+        interrupt: Interrupt,
+        handler: unsafe extern "C" fn(),
+    ) {
+        let id = reg(VTFIDR + slot as usize) as *mut u8;
+        let addr = reg(VTFADDRR + 4 * slot as usize);
+
+        // Disable the slot while it is reconfigured
+        core::ptr::write_volatile(addr, 0);
+        core::ptr::write_volatile(id, interrupt as u8);
+        core::ptr::write_volatile(addr, (handler as usize as u32 & !VTFADDRR_EN) | VTFADDRR_EN);
+    }
+
+    /// Re-enable a VTF slot after [`disable_vtf`](Pfic::disable_vtf)
+    ///
+    /// # Safety
+    ///
+    /// The slot must have been configured with [`set_vtf`](Pfic::set_vtf)
+    pub unsafe fn enable_vtf(&mut self, slot: VtfSlot) {
+        let addr = reg(VTFADDRR + 4 * slot as usize);
+        core::ptr::write_volatile(addr, core::ptr::read_volatile(addr) | VTFADDRR_EN);
+    }
+
+    /// Stop using a VTF slot, its interrupt is dispatched through the vector table again
+    pub fn disable_vtf(&mut self, slot: VtfSlot) {
+        let addr = reg(VTFADDRR + 4 * slot as usize);
+        // NOTE(unsafe) the slot registers are only written through `&mut Pfic`
+        unsafe { core::ptr::write_volatile(addr, core::ptr::read_volatile(addr) & !VTFADDRR_EN) };
+    }
+
+    /// Returns `true` if the VTF slot is enabled
+    pub fn is_vtf_enabled(slot: VtfSlot) -> bool {
+        let addr = reg(VTFADDRR + 4 * slot as usize);
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { core::ptr::read_volatile(addr) & VTFADDRR_EN != 0 }
+    }
+
+    /// Request a system reset, which resets the core and all peripherals
+    #[inline]
+    pub fn system_reset() -> ! {
+        // NOTE(unsafe) the write does not return
+        unsafe { core::ptr::write_volatile(reg(CFGR), CFGR_KEY3 | CFGR_RESETSYS) };
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Releases the PFIC peripheral
+    pub fn free(self) -> PFIC {
+        self.pfic
+    }
+}
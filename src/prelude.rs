@@ -7,3 +7,9 @@ pub use crate::rcc::RccExt as _;
 pub use crate::serial::UsartExt as _;
 
 pub use crate::gpio::GpioExt as _;
+
+pub use crate::gpio::ExtiPin as _;
+
+pub use crate::pfic::PficExt as _;
+
+pub use crate::extend::ExtendExt as _;
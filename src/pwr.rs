@@ -1,10 +1,59 @@
 //! Power Control (PWR)
+//!
+//! # Low-power modes
+//!
+//! - [`Pwr::sleep`] stops the core clock, peripherals keep running.
+//! - [`Pwr::standby`] also stops HSI, HSE and PLL. SRAM and registers are retained and
+//!   execution resumes after the wakeup, running from HSI. The clocks are restored
+//!   from the [`rcc::Config`] the `Pwr` was created with, or from HSI if the HSE
+//!   failed.
+//!
+//! With [`WaitMode::Wfi`] any enabled interrupt wakes the core up, with
+//! [`WaitMode::Wfe`] any EXTI event does, see [`ExtiPin::enable_event`](crate::gpio::ExtiPin::enable_event)
+//! and [`Pwr::enable_awu`].
 
 use crate::{
-    pac::PWR,
-    rcc::{Clocks, Enable, Rcc},
+    gpio::Edge,
+    pac::{EXTI, PWR, RCC},
+    rcc::{self, Clocks, Enable, Rcc},
+    time::MilliSeconds,
 };
 
+/// System control register of the PFIC
+const PFIC_SCTLR: *mut u32 = 0xE000_ED10 as *mut u32;
+/// Enter deep sleep (standby) instead of sleep
+const SCTLR_SLEEPDEEP: u32 = 1 << 2;
+/// Execute WFI as WFE
+const SCTLR_WFITOWFE: u32 = 1 << 3;
+
+/// EXTI line of the PVD output
+const PVD_EXTI_LINE: u32 = 8;
+/// EXTI line of the auto-wakeup timer
+const AWU_EXTI_LINE: u32 = 9;
+
+/// LSI frequency in Hz, clocking the AWU
+const LSI_HZ: u32 = 128_000;
+/// Largest AWU window value
+const AWU_MAX_WINDOW: u32 = 0x3F;
+/// AWU prescaler register values and their division factors
+const AWU_PRESCALERS: [(u8, u32); 15] = [
+    (0b0000, 1),
+    (0b0010, 2),
+    (0b0011, 4),
+    (0b0100, 8),
+    (0b0101, 16),
+    (0b0110, 32),
+    (0b0111, 64),
+    (0b1000, 128),
+    (0b1001, 256),
+    (0b1010, 512),
+    (0b1011, 1024),
+    (0b1100, 2048),
+    (0b1101, 4096),
+    (0b1110, 10240),
+    (0b1111, 61440),
+];
+
 pub enum PVDVoltageThreshold {
     Rising2_85Falling2_7 = 0b000,
     Rising3_05Falling2_9 = 0b001,
@@ -16,16 +65,37 @@ pub enum PVDVoltageThreshold {
     Rising4_4Falling4_2 = 0b111,
 }
 
+/// Supply voltage relative to the PVD threshold
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PvdStatus {
+    AboveThreshold,
+    BelowThreshold,
+}
+
+/// Instruction used to enter a low-power mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitMode {
+    /// Wait for interrupt
+    Wfi,
+    /// Wait for event
+    Wfe,
+}
+
 pub struct Pwr {
     pwr: PWR,
-    _clocks: Clocks,
+    config: rcc::Config,
 }
 
 impl Pwr {
-    pub fn pwr(pwr: PWR, rcc: &mut Rcc, _clocks: Clocks) -> Self {
+    /// The clock configuration of `rcc` is restored after waking up from standby
+    #[allow(clippy::self_named_constructors)]
+    pub fn pwr(pwr: PWR, rcc: &mut Rcc) -> Self {
         PWR::enable(&mut rcc.apb1);
 
-        Self { pwr, _clocks }
+        Self {
+            pwr,
+            config: rcc.config,
+        }
     }
 
     /// set threshold voltage for pvd
@@ -45,8 +115,150 @@ impl Pwr {
         self.pwr.ctlr.modify(|_, w| w.pvde().clear_bit());
     }
 
-    /// pvd output: returns 1 if vdd is above threshold voltage
-    pub fn pvd_output(&mut self) -> bool {
-        self.pwr.csr.read().pvdo().bit_is_clear()
+    /// Compare VDD to the PVD threshold, the PVD must be enabled
+    pub fn pvd_output(&mut self) -> PvdStatus {
+        // PVDO is set while VDD is below the threshold
+        if self.pwr.csr.read().pvdo().bit_is_set() {
+            PvdStatus::BelowThreshold
+        } else {
+            PvdStatus::AboveThreshold
+        }
+    }
+
+    /// Raise the PVD interrupt when VDD crosses the threshold
+    ///
+    /// The PVD output is connected to EXTI line 8: [`Edge::Rising`] triggers when VDD
+    /// falls below the threshold, [`Edge::Falling`] when it rises above it again.
+    /// The PVD interrupt has to be enabled in the PFIC.
+    pub fn listen_pvd(&mut self, edge: Edge, exti: &mut EXTI) {
+        let mask = 1 << PVD_EXTI_LINE;
+        let (rising, falling) = match edge {
+            Edge::Rising => (true, false),
+            Edge::Falling => (false, true),
+            Edge::Both => (true, true),
+        };
+        exti.rtenr.modify(|r, w| unsafe {
+            w.bits(if rising {
+                r.bits() | mask
+            } else {
+                r.bits() & !mask
+            })
+        });
+        exti.ftenr.modify(|r, w| unsafe {
+            w.bits(if falling {
+                r.bits() | mask
+            } else {
+                r.bits() & !mask
+            })
+        });
+        exti.intenr
+            .modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+    }
+
+    /// Stop raising the PVD interrupt
+    pub fn unlisten_pvd(&mut self, exti: &mut EXTI) {
+        let mask = 1 << PVD_EXTI_LINE;
+        exti.intenr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+        exti.rtenr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+        exti.ftenr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    }
+
+    /// Clear the pending PVD interrupt, call this from the PVD interrupt handler
+    pub fn clear_pvd_pending(&mut self) {
+        // NOTE(unsafe) write 1 to clear, other lines are not affected
+        unsafe { (*EXTI::ptr()).intfr.write(|w| w.bits(1 << PVD_EXTI_LINE)) };
+    }
+
+    /// Wake up periodically from the auto-wakeup timer
+    ///
+    /// The period is rounded to the resolution of the prescaler selected for it, from
+    /// 1 ms up to about 30 s. The LSI is started if it is not running. The AWU raises
+    /// an event on EXTI line 9, use [`WaitMode::Wfe`] to wake up on it, or enable the
+    /// line's interrupt for [`WaitMode::Wfi`].
+    pub fn enable_awu(&mut self, period: MilliSeconds, exti: &mut EXTI) {
+        // NOTE(unsafe) LSION is only set, other bits of RSTSCKR are preserved
+        let rcc = unsafe { &(*RCC::ptr()) };
+        rcc.rstsckr.modify(|_, w| w.lsion().set_bit());
+        while rcc.rstsckr.read().lsirdy().bit_is_clear() {}
+
+        let ticks = (period.ticks() as u64 * LSI_HZ as u64 / 1000).max(1);
+        let (psc, div) = AWU_PRESCALERS
+            .iter()
+            .copied()
+            .find(|(_, div)| ticks <= (AWU_MAX_WINDOW * div) as u64)
+            .unwrap_or(AWU_PRESCALERS[AWU_PRESCALERS.len() - 1]);
+        let window = (ticks / div as u64).clamp(1, AWU_MAX_WINDOW as u64) as u32;
+
+        let mask = 1 << AWU_EXTI_LINE;
+        exti.rtenr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        exti.evenr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+
+        self.pwr.awupsc.write(|w| unsafe { w.bits(psc as u32) });
+        self.pwr.awuapr.write(|w| unsafe { w.bits(window) });
+        self.pwr.awucsr.modify(|_, w| w.awuen().set_bit());
+    }
+
+    /// Stop the auto-wakeup timer
+    pub fn disable_awu(&mut self, exti: &mut EXTI) {
+        self.pwr.awucsr.modify(|_, w| w.awuen().clear_bit());
+
+        let mask = 1 << AWU_EXTI_LINE;
+        exti.evenr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+        exti.rtenr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    }
+
+    /// Enter sleep mode until woken up according to `mode`
+    pub fn sleep(&mut self, mode: WaitMode) {
+        self.pwr.ctlr.modify(|_, w| w.pdds().clear_bit());
+        // NOTE(unsafe) SCTLR is only modified while entering low-power modes
+        unsafe { set_sctlr(SCTLR_SLEEPDEEP, false) };
+        wait(mode);
+    }
+
+    /// Enter standby mode until woken up according to `mode`
+    ///
+    /// Returns the clocks restored from the configuration the `Pwr` was created with.
+    /// If the HSE failed, as reported by [`css::is_failed`](rcc::css::is_failed), or
+    /// does not start again, the clocks are restored from HSI like
+    /// [`css::on_nmi`](rcc::css::on_nmi) does.
+    pub fn standby(&mut self, mode: WaitMode) -> Clocks {
+        self.pwr.ctlr.modify(|_, w| w.pdds().set_bit());
+        // NOTE(unsafe) SCTLR is only modified while entering low-power modes
+        unsafe { set_sctlr(SCTLR_SLEEPDEEP, true) };
+
+        wait(mode);
+
+        unsafe { set_sctlr(SCTLR_SLEEPDEEP, false) };
+        self.pwr.ctlr.modify(|_, w| w.pdds().clear_bit());
+
+        let config = if rcc::css::is_failed() {
+            self.config.hsi_fallback()
+        } else {
+            self.config
+        };
+        config
+            .try_freeze()
+            .unwrap_or_else(|_| config.hsi_fallback().freeze())
+    }
+}
+
+unsafe fn set_sctlr(mask: u32, set: bool) {
+    let sctlr = core::ptr::read_volatile(PFIC_SCTLR);
+    let sctlr = if set { sctlr | mask } else { sctlr & !mask };
+    core::ptr::write_volatile(PFIC_SCTLR, sctlr);
+}
+
+/// Execute WFI, as WFE if requested
+fn wait(mode: WaitMode) {
+    // NOTE(unsafe) SCTLR is only modified while entering low-power modes
+    unsafe {
+        set_sctlr(SCTLR_WFITOWFE, mode == WaitMode::Wfe);
+        core::arch::asm!("wfi");
+        set_sctlr(SCTLR_WFITOWFE, false);
     }
 }
@@ -0,0 +1,128 @@
+//! Clock security system (CSS)
+//!
+//! With [`Config::enable_css`](super::Config::enable_css) set, a failure of the HSE
+//! makes the hardware turn off the HSE, and the PLL if it is fed from the HSE, switch
+//! the system clock to HSI and raise the NMI. The NMI handler has to call [`on_nmi`],
+//! which restores the clocks from HSI and calls the hook registered with
+//! [`set_hook`]:
+//!
+//! ```ignore
+//! #[qingke_rt::interrupt(core)]
+//! fn NonMaskableInt() {
+//!     hal::rcc::css::on_nmi();
+//! }
+//! ```
+//!
+//! The fallback configuration is the frozen one, with HSI instead of the HSE as system
+//! clock and PLL source, and without the HSE clock output. The CSS is disabled afterwards, freeze the
+//! original configuration again to go back to the HSE once it is working.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::{Clocks, Config, RCC};
+
+/// Clock security system interrupt flag, in INTR
+const INTR_CSSF: u32 = 1 << 7;
+/// Clear the clock security system interrupt flag, in INTR
+const INTR_CSSC: u32 = 1 << 23;
+
+/// Value of `ACTIVE` while there is no fallback configuration
+const NO_FALLBACK: usize = 2;
+
+/// Slot holding a fallback configuration
+struct Slot(UnsafeCell<Config>);
+
+// NOTE(unsafe) a slot is only written while it is not selected by `ACTIVE`, and only
+// the selected one is read. Critical sections do not mask the NMI, so the selection is
+// switched with a single atomic store. The NMI handler never writes a slot, the
+// fallback configuration it freezes has the CSS disabled.
+unsafe impl Sync for Slot {}
+
+/// Configurations restored after an HSE failure, set while freezing with CSS enabled
+static FALLBACK: [Slot; 2] = [
+    Slot(UnsafeCell::new(Config::DEFAULT)),
+    Slot(UnsafeCell::new(Config::DEFAULT)),
+];
+/// Index of the slot read by `on_nmi`, or `NO_FALLBACK`
+static ACTIVE: AtomicUsize = AtomicUsize::new(NO_FALLBACK);
+/// Called with the fallback clocks, `fn(&Clocks)` stored as address, 0 if unset
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+/// Set when an HSE failure was handled
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Register `hook`, called from [`on_nmi`] with the fallback clocks
+///
+/// Use it to reconfigure the peripherals that depend on the clock frequencies, such
+/// as baud rates and timer prescalers.
+pub fn set_hook(hook: fn(&Clocks)) {
+    HOOK.store(hook as usize, Ordering::Release);
+}
+
+/// Remove the hook registered with [`set_hook`]
+pub fn clear_hook() {
+    HOOK.store(0, Ordering::Release);
+}
+
+/// Returns `true` if an HSE failure was detected since the last [`clear`]
+pub fn is_failed() -> bool {
+    FAILED.load(Ordering::Acquire)
+}
+
+/// Clear the HSE failure reported by [`is_failed`]
+pub fn clear() {
+    FAILED.store(false, Ordering::Release);
+}
+
+/// Handle an HSE failure, call this from the NMI handler
+///
+/// Returns the fallback clocks if the NMI was raised by the CSS, `None` otherwise.
+pub fn on_nmi() -> Option<Clocks> {
+    // NOTE(unsafe) CSSC only clears the CSS flag
+    let rcc = unsafe { &*RCC::ptr() };
+    if rcc.intr.read().bits() & INTR_CSSF == 0 {
+        return None;
+    }
+    rcc.intr
+        .modify(|r, w| unsafe { w.bits(r.bits() | INTR_CSSC) });
+    FAILED.store(true, Ordering::Release);
+
+    // The hardware already switched to HSI, run from it if there is nothing to restore
+    let fallback = match ACTIVE.load(Ordering::Acquire) {
+        NO_FALLBACK => Config::default(),
+        // NOTE(unsafe) the active slot is not written until another one is selected
+        active => unsafe { *FALLBACK[active].0.get() },
+    };
+    let clocks = match fallback.try_freeze() {
+        Ok(clocks) => clocks,
+        // Only the HSI is used, the default configuration starts in any case
+        Err(_) => Config::default().freeze(),
+    };
+
+    let hook = HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        // NOTE(unsafe) only addresses of `fn(&Clocks)` are stored in HOOK
+        let hook = unsafe { core::mem::transmute::<usize, fn(&Clocks)>(hook) };
+        hook(&clocks);
+    }
+    Some(clocks)
+}
+
+/// Store the configuration to restore after an HSE failure, `None` disables it
+pub(super) fn set_fallback(config: Option<Config>) {
+    let Some(config) = config else {
+        ACTIVE.store(NO_FALLBACK, Ordering::Release);
+        return;
+    };
+
+    // Writers are serialized, the NMI only reads
+    critical_section::with(|_| {
+        let next = match ACTIVE.load(Ordering::Relaxed) {
+            0 => 1,
+            _ => 0,
+        };
+        // NOTE(unsafe) the slot is not selected, `on_nmi` does not read it
+        unsafe { *FALLBACK[next].0.get() = config };
+        ACTIVE.store(next, Ordering::Release);
+    });
+}
@@ -0,0 +1,168 @@
+//! HSI trimming and calibration
+//!
+//! The HSI is calibrated in the factory to 24MHz at room temperature, the calibration
+//! is loaded into HSICAL on reset. HSITRIM adds a user adjustment on top of it, which
+//! [`Rcc::calibrate_hsi`] searches by measuring a reference of known frequency with
+//! the HSI-derived clocks, e.g. a pulse train captured by a timer:
+//!
+//! ```ignore
+//! let pwm_input = Timer::new(p.TIM2, &clocks).pwm_input::<Tim2NoRemap, _>(
+//!     (gpiod.pd4, gpiod.pd3),
+//!     Configuration::Frequency(1.kHz()),
+//! );
+//! let clocks = rcc.calibrate_hsi(&clocks, 1.kHz(), 24.kHz(), |clocks| {
+//!     pwm_input.read_frequency(ReadMode::WaitForNextCapture, clocks).ok()
+//! })?;
+//! ```
+//!
+//! The HSE can not be measured internally. To use it as reference, output it on the
+//! MCO pin with [`MCO::Hse`](super::MCO::Hse) and connect that pin to a timer input on
+//! the board. The result only holds for the current supply voltage and temperature,
+//! calibrate again when they change.
+
+use super::{ClockError, ClockSrc, Clocks, Hertz, PLLSrc, Rcc, HSI_FREQUENCY, HSI_TRIM_MAX, RCC};
+use crate::pac::rcc::RegisterBlock;
+
+const CTLR_HSITRIM_SHIFT: u32 = 3;
+const CTLR_HSITRIM_MASK: u32 = 0x1F << CTLR_HSITRIM_SHIFT;
+const CTLR_HSICAL_SHIFT: u32 = 8;
+
+/// HSI calibration error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationError {
+    /// The system clock is not derived from HSI, trimming HSI does not affect it
+    NotRunningFromHsi,
+    /// The reference could not be measured
+    Measurement,
+    /// The closest trim value leaves the HSI at this frequency, outside of the
+    /// tolerance
+    OutOfTolerance(Hertz),
+    /// The clock configuration is invalid
+    Clock(ClockError),
+}
+
+/// Set HSITRIM, `trim` must be in range
+pub(super) fn set_trim(rcc: &RegisterBlock, trim: u8) {
+    rcc.ctlr.modify(|r, w| unsafe {
+        w.bits((r.bits() & !CTLR_HSITRIM_MASK) | ((trim as u32) << CTLR_HSITRIM_SHIFT))
+    });
+}
+
+impl Rcc {
+    /// Returns the factory calibration of the HSI
+    pub fn hsi_calibration(&self) -> u8 {
+        // NOTE(unsafe) atomic read with no side effects
+        let ctlr = unsafe { (*RCC::ptr()).ctlr.read().bits() };
+        (ctlr >> CTLR_HSICAL_SHIFT) as u8
+    }
+
+    /// Returns the HSI trim value
+    pub fn hsi_trim(&self) -> u8 {
+        // NOTE(unsafe) atomic read with no side effects
+        let ctlr = unsafe { (*RCC::ptr()).ctlr.read().bits() };
+        ((ctlr & CTLR_HSITRIM_MASK) >> CTLR_HSITRIM_SHIFT) as u8
+    }
+
+    /// Set the HSI trim value, 0-31
+    ///
+    /// The value is kept in [`config`](Rcc::config), so it is applied again by the
+    /// next freeze. The HSI frequency of the configuration is reset to the typical
+    /// 24MHz.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `trim` is above 31.
+    pub fn set_hsi_trim(&mut self, trim: u8) {
+        assert!(trim <= HSI_TRIM_MAX);
+        // NOTE(unsafe) HSITRIM is only written through `&mut Rcc` and `freeze`
+        set_trim(unsafe { &*RCC::ptr() }, trim);
+        self.config.hsi_trim = trim;
+        self.config.hsi_frequency = HSI_FREQUENCY;
+    }
+
+    /// Trim the HSI as close as possible to 24MHz
+    ///
+    /// `measure` measures the frequency of a reference with the HSI-derived
+    /// `clocks`, which must be the current ones. The reference actually runs at
+    /// `reference`, the difference gives the HSI frequency. `measure` is called
+    /// after every change of the trim value and has to wait for a fresh measurement.
+    ///
+    /// The trim value and the measured HSI frequency are stored in
+    /// [`config`](Rcc::config), the returned clocks are computed from them. If
+    /// the HSI can not be brought within `tolerance` of 24MHz,
+    /// [`CalibrationError::OutOfTolerance`] is returned with the frequency of the
+    /// closest trim value, and the previous trim value is restored.
+    pub fn calibrate_hsi<F>(
+        &mut self,
+        clocks: &Clocks,
+        reference: Hertz,
+        tolerance: Hertz,
+        mut measure: F,
+    ) -> Result<Clocks, CalibrationError>
+    where
+        F: FnMut(&Clocks) -> Option<Hertz>,
+    {
+        if !matches!(
+            (self.config.mux, self.config.pll),
+            (ClockSrc::Hsi, _) | (ClockSrc::Pll, PLLSrc::Hsi)
+        ) {
+            return Err(CalibrationError::NotRunningFromHsi);
+        }
+
+        // NOTE(unsafe) HSITRIM is only written through `&mut Rcc` and `freeze`
+        let rcc = unsafe { &*RCC::ptr() };
+        let mut measure_hsi = |trim: u8| -> Result<Hertz, CalibrationError> {
+            set_trim(rcc, trim);
+            let measured = measure(clocks)
+                .filter(|f| f.raw() != 0)
+                .ok_or(CalibrationError::Measurement)?;
+            // A fast HSI makes the reference look slow, and the other way around
+            let hsi = clocks.hsi.raw() as u64 * reference.raw() as u64 / measured.raw() as u64;
+            Ok(Hertz::from_raw(hsi as u32))
+        };
+        let error = |hsi: Hertz| hsi.raw().abs_diff(HSI_FREQUENCY.raw());
+
+        let mut search = || -> Result<(u8, Hertz), CalibrationError> {
+            // The HSI frequency rises with the trim value, find the lowest value at or
+            // above 24MHz
+            let (mut low, mut high) = (0, HSI_TRIM_MAX);
+            while low < high {
+                let mid = (low + high) / 2;
+                if measure_hsi(mid)? < HSI_FREQUENCY {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
+            }
+
+            // The value below may be closer
+            let mut best = (low, measure_hsi(low)?);
+            if low > 0 {
+                let below = measure_hsi(low - 1)?;
+                if error(below) < error(best.1) {
+                    best = (low - 1, below);
+                }
+            }
+            Ok(best)
+        };
+        let result = match search() {
+            Ok((_, hsi)) if error(hsi) > tolerance.raw() => {
+                Err(CalibrationError::OutOfTolerance(hsi))
+            }
+            result => result,
+        };
+        let (trim, hsi) = match result {
+            Ok(best) => best,
+            Err(e) => {
+                // Restore the previous trim value
+                set_trim(rcc, self.config.hsi_trim);
+                return Err(e);
+            }
+        };
+
+        set_trim(rcc, trim);
+        self.config.hsi_trim = trim;
+        self.config.hsi_frequency = hsi;
+        self.config.clocks().map_err(CalibrationError::Clock)
+    }
+}
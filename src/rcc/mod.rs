@@ -2,11 +2,19 @@
 
 use core::ops::Div;
 
+pub mod css;
 mod enable;
+mod hsi;
+mod reset;
+
+pub use hsi::CalibrationError;
+
+pub use reset::{reset_into_bootloader, system_reset, ResetReason};
 
 use ch32v0::{Readable, Reg, Writable};
 use fugit::{HertzU32 as Hertz, RateExtU32};
 
+use crate::flash::{self, Latency};
 use crate::pac::{
     rcc::{self, cfgr0::CFGR0_SPEC},
     RCC,
@@ -15,6 +23,48 @@ use crate::pac::{
 /// Typical output frequency of the HSI oscillator.
 const HSI_FREQUENCY: Hertz = Hertz::from_raw(24_000_000);
 
+/// HSITRIM value on reset, the middle of its range.
+const HSI_TRIM_DEFAULT: u8 = 16;
+/// Largest HSITRIM value.
+const HSI_TRIM_MAX: u8 = 0x1F;
+
+/// Typical output frequency of the LSI oscillator.
+const LSI_FREQUENCY: Hertz = Hertz::from_raw(128_000);
+
+/// Supported HSE frequency range, from the datasheet.
+const HSE_MIN_FREQUENCY: Hertz = Hertz::from_raw(4_000_000);
+const HSE_MAX_FREQUENCY: Hertz = Hertz::from_raw(25_000_000);
+
+/// Highest system clock frequency, from the datasheet.
+const SYSCLK_MAX_FREQUENCY: Hertz = Hertz::from_raw(48_000_000);
+
+/// Highest ADC clock frequency, from the datasheet.
+const ADC_MAX_FREQUENCY: Hertz = Hertz::from_raw(24_000_000);
+
+/// Number of polls before an oscillator or the PLL is considered failed to start.
+const STARTUP_TIMEOUT: u32 = 0x8000;
+
+/// Invalid clock configuration or clock startup failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockError {
+    /// HSE is used as system clock, PLL source or clock output but not configured
+    HseNotConfigured,
+    /// The HSE frequency is outside of 4-25MHz
+    HseFrequency,
+    /// The system clock is above 48MHz
+    SysclkFrequency,
+    /// The ADC clock is above 24MHz, the ADC prescaler has to be raised
+    AdcFrequency,
+    /// The PLL is selected as clock output but not used as system clock
+    PllNotEnabled,
+    /// The HSI trim value is above 31
+    HsiTrim,
+    /// The HSE did not become ready
+    HseTimeout,
+    /// The PLL did not lock
+    PllTimeout,
+}
+
 /// Extension trait that constrains the `RCC` peripheral
 pub trait RccExt {
     /// Constrains the `RCC` peripheral so it plays nicely with the other abstractions
@@ -98,27 +148,22 @@ impl Default for HSEConfig {
 }
 
 /// HSE clock source
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum HSESrc {
     /// Crystal/ceramic resonator
+    #[default]
     Crystal,
     /// External clock source, HSE bypassed
     Bypass,
 }
 
-impl Default for HSESrc {
-    fn default() -> Self {
-        Self::Crystal
-    }
-}
-
 /// Source of core clock signal
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum ClockSrc {
     /// 24MHz internal RC oscillator
     Hsi = 0b00,
-    /// High speed external oscillator, 5-25Mhz
+    /// High speed external oscillator, 4-25Mhz
     Hse = 0b01,
     /// Internal phase locked loop
     Pll = 0b10,
@@ -143,28 +188,80 @@ pub enum AHBPrescaler {
     Div256 = 0b1111,
 }
 
+impl AHBPrescaler {
+    /// Division factor of the prescaler
+    pub const fn divisor(self) -> u32 {
+        match self {
+            AHBPrescaler::NotDivided => 1,
+            AHBPrescaler::Div2 => 2,
+            AHBPrescaler::Div3 => 3,
+            AHBPrescaler::Div4 => 4,
+            AHBPrescaler::Div5 => 5,
+            AHBPrescaler::Div6 => 6,
+            AHBPrescaler::Div7 => 7,
+            AHBPrescaler::Div8 => 8,
+            AHBPrescaler::Div16 => 16,
+            AHBPrescaler::Div32 => 32,
+            AHBPrescaler::Div64 => 64,
+            AHBPrescaler::Div128 => 128,
+            AHBPrescaler::Div256 => 256,
+        }
+    }
+}
+
 impl Div<AHBPrescaler> for Hertz {
     type Output = Hertz;
 
     fn div(self, rhs: AHBPrescaler) -> Self::Output {
-        match rhs {
-            AHBPrescaler::NotDivided => self,
-            AHBPrescaler::Div2 => self / 2,
-            AHBPrescaler::Div3 => self / 3,
-            AHBPrescaler::Div4 => self / 4,
-            AHBPrescaler::Div5 => self / 5,
-            AHBPrescaler::Div6 => self / 6,
-            AHBPrescaler::Div7 => self / 7,
-            AHBPrescaler::Div8 => self / 8,
-            AHBPrescaler::Div16 => self / 16,
-            AHBPrescaler::Div32 => self / 32,
-            AHBPrescaler::Div64 => self / 64,
-            AHBPrescaler::Div128 => self / 128,
-            AHBPrescaler::Div256 => self / 256,
+        self / rhs.divisor()
+    }
+}
+
+/// ADC clock prescaler, dividing HCLK
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ADCPrescaler {
+    Div2 = 0b00000,
+    Div4 = 0b01000,
+    Div6 = 0b10000,
+    Div8 = 0b11000,
+    Div12 = 0b10100,
+    Div16 = 0b11100,
+    Div24 = 0b10101,
+    Div32 = 0b11101,
+    Div48 = 0b10110,
+    Div64 = 0b11110,
+    Div96 = 0b10111,
+    Div128 = 0b11111,
+}
+
+impl ADCPrescaler {
+    /// Division factor of the prescaler
+    pub const fn divisor(self) -> u32 {
+        match self {
+            ADCPrescaler::Div2 => 2,
+            ADCPrescaler::Div4 => 4,
+            ADCPrescaler::Div6 => 6,
+            ADCPrescaler::Div8 => 8,
+            ADCPrescaler::Div12 => 12,
+            ADCPrescaler::Div16 => 16,
+            ADCPrescaler::Div24 => 24,
+            ADCPrescaler::Div32 => 32,
+            ADCPrescaler::Div48 => 48,
+            ADCPrescaler::Div64 => 64,
+            ADCPrescaler::Div96 => 96,
+            ADCPrescaler::Div128 => 128,
         }
     }
 }
 
+impl Div<ADCPrescaler> for Hertz {
+    type Output = Hertz;
+
+    fn div(self, rhs: ADCPrescaler) -> Self::Output {
+        self / rhs.divisor()
+    }
+}
+
 /// Source for the internal phase locked loop
 #[derive(Clone, Copy, PartialEq)]
 #[repr(u8)]
@@ -200,31 +297,153 @@ pub struct Config {
     pub hse: Option<HSEConfig>,
     /// Phase locked loop (2x multiplier)
     pub pll: PLLSrc,
+    /// HSI trim value, 0-31, added to the factory calibration. Higher values raise
+    /// the HSI frequency
+    pub hsi_trim: u8,
+    /// Actual HSI frequency at `hsi_trim`, see [`Rcc::calibrate_hsi`]
+    ///
+    /// The clocks derived from HSI are computed from it, the frequency limits are
+    /// still checked with the typical 24MHz.
+    pub hsi_frequency: Hertz,
     /// Enable internal 128Khz clock. Cannot be used as core clock source
     pub enable_lsi: bool,
+    /// Enable the HSE clock security system, see [`css`]
+    pub enable_css: bool,
     /// Which clock feeds the core frequency
     pub mux: ClockSrc,
     /// AHB bus frequency prescaler
     pub ahb_pre: AHBPrescaler,
+    /// ADC clock prescaler, the ADC clock must not exceed 24MHz
+    pub adc_pre: ADCPrescaler,
     /// Clock output configuration
     pub mco: MCO,
 }
 
 impl Default for Config {
-    // 24MHz HSI
     fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Config {
+    // 24MHz HSI
+    const DEFAULT: Self = Self {
+        hse: None,
+        pll: PLLSrc::Hsi,
+        hsi_trim: HSI_TRIM_DEFAULT,
+        hsi_frequency: HSI_FREQUENCY,
+        enable_lsi: false,
+        enable_css: false,
+        mux: ClockSrc::Hsi,
+        ahb_pre: AHBPrescaler::NotDivided,
+        adc_pre: ADCPrescaler::Div2,
+        mco: MCO::None,
+    };
+
+    /// Compute the clocks resulting from this configuration, without touching the
+    /// hardware
+    ///
+    /// Returns an error if the configuration is invalid, see [`ClockError`].
+    pub const fn clocks(&self) -> Result<Clocks, ClockError> {
+        if self.hsi_trim > HSI_TRIM_MAX {
+            return Err(ClockError::HsiTrim);
+        }
+
+        let hse = match self.hse {
+            Some(hse) => {
+                let hz = hse.frequency.raw();
+                if hz < HSE_MIN_FREQUENCY.raw() || hz > HSE_MAX_FREQUENCY.raw() {
+                    return Err(ClockError::HseFrequency);
+                }
+                Some(hse.frequency)
+            }
+            None => None,
+        };
+
+        let sysclk = match (self.mux, self.pll, hse) {
+            (ClockSrc::Hsi, _, _) => HSI_FREQUENCY,
+            (ClockSrc::Hse, _, Some(hse)) => hse,
+            (ClockSrc::Pll, PLLSrc::Hsi, _) => Hertz::from_raw(HSI_FREQUENCY.raw() * 2),
+            (ClockSrc::Pll, PLLSrc::Hse, Some(hse)) => Hertz::from_raw(hse.raw() * 2),
+            (_, _, None) => return Err(ClockError::HseNotConfigured),
+        };
+        if sysclk.raw() > SYSCLK_MAX_FREQUENCY.raw() {
+            return Err(ClockError::SysclkFrequency);
+        }
+        let pllclk = match self.mux {
+            ClockSrc::Pll => Some(sysclk),
+            _ => None,
+        };
+
+        if self.enable_css && hse.is_none() {
+            return Err(ClockError::HseNotConfigured);
+        }
+
+        match (self.mco, hse, pllclk) {
+            (MCO::Hse, None, _) => return Err(ClockError::HseNotConfigured),
+            (MCO::Pll, _, None) => return Err(ClockError::PllNotEnabled),
+            _ => {}
+        }
+
+        let hclk = Hertz::from_raw(sysclk.raw() / self.ahb_pre.divisor());
+        let adcclk = Hertz::from_raw(hclk.raw() / self.adc_pre.divisor());
+        if adcclk.raw() > ADC_MAX_FREQUENCY.raw() {
+            return Err(ClockError::AdcFrequency);
+        }
+
+        let flash_latency = Latency::for_sysclk(sysclk);
+
+        // Scale the clocks derived from HSI to its actual frequency
+        let (sysclk, hclk, adcclk, pllclk) = match (self.mux, self.pll) {
+            (ClockSrc::Hsi, _) | (ClockSrc::Pll, PLLSrc::Hsi) => {
+                let hsi = self.hsi_frequency;
+                (
+                    scale_hsi(sysclk, hsi),
+                    scale_hsi(hclk, hsi),
+                    scale_hsi(adcclk, hsi),
+                    match pllclk {
+                        Some(pllclk) => Some(scale_hsi(pllclk, hsi)),
+                        None => None,
+                    },
+                )
+            }
+            _ => (sysclk, hclk, adcclk, pllclk),
+        };
+
+        Ok(Clocks {
+            sysclk,
+            hclk,
+            adcclk,
+            pllclk,
+            hsi: self.hsi_frequency,
+            hse,
+            lsi: if self.enable_lsi {
+                Some(LSI_FREQUENCY)
+            } else {
+                None
+            },
+            flash_latency,
+        })
+    }
+
+    /// Configuration running from HSI, restored after an HSE failure
+    pub(crate) fn hsi_fallback(&self) -> Self {
         Self {
             hse: None,
             pll: PLLSrc::Hsi,
-            enable_lsi: false,
-            mux: ClockSrc::Hsi,
-            ahb_pre: AHBPrescaler::NotDivided,
-            mco: MCO::None,
+            enable_css: false,
+            mux: match self.mux {
+                ClockSrc::Hse => ClockSrc::Hsi,
+                mux => mux,
+            },
+            mco: match self.mco {
+                MCO::Hse => MCO::None,
+                mco => mco,
+            },
+            ..*self
         }
     }
-}
 
-impl Config {
     /// Configure the "mandatory" clocks (`sysclk`, `hclk`, `pclk1` and `pclk2')
     /// and return them via the `Clocks` struct.
     ///
@@ -234,10 +453,25 @@ impl Config {
     /// The implementation makes the following choice: HSI is always chosen over
     /// HSE except when HSE is provided. When HSE is provided, HSE is used
     /// wherever it is possible.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid or an oscillator fails to start, see
+    /// [`try_freeze`](Config::try_freeze).
     pub fn freeze(self) -> Clocks {
-        let rcc = unsafe { &(*RCC::ptr()) };
+        self.try_freeze().expect("invalid clock configuration")
+    }
+
+    /// Configure the clocks like [`freeze`](Config::freeze), returning an error
+    /// instead of panicking
+    ///
+    /// The configuration is validated before the hardware is touched. If the HSE or
+    /// the PLL then fails to start, both are turned off and the system keeps running
+    /// from HSI, with the prescalers, the flash latency and the HSI trim unchanged.
+    pub fn try_freeze(self) -> Result<Clocks, ClockError> {
+        let clocks = self.clocks()?;
 
-        let mut clocks = Clocks::default();
+        let rcc = unsafe { &(*RCC::ptr()) };
 
         // Helper function to write to a register and block until condition is met
         fn block<REG>(
@@ -251,6 +485,25 @@ impl Config {
             while !get(reg.read()) {}
         }
 
+        // Like `block`, but gives up after `STARTUP_TIMEOUT` polls
+        fn block_timeout<REG>(
+            reg: &Reg<REG>,
+            set: impl Fn(&mut REG::Writer) -> &mut REG::Writer,
+            get: impl Fn(REG::Reader) -> bool,
+            error: ClockError,
+        ) -> Result<(), ClockError>
+        where
+            REG: Readable + Writable,
+        {
+            reg.modify(|_, w| set(w));
+            for _ in 0..STARTUP_TIMEOUT {
+                if get(reg.read()) {
+                    return Ok(());
+                }
+            }
+            Err(error)
+        }
+
         // Helper to set clock source blockingly
         fn block_clock(cfgr0: &Reg<CFGR0_SPEC>, src: ClockSrc) {
             block(
@@ -275,63 +528,77 @@ impl Config {
                 HSESrc::Bypass => rcc.ctlr.modify(|_, w| w.hsebyp().set_bit()),
             }
             // Start HSE, wait for it to stabilize
-            block(
+            if let Err(e) = block_timeout(
                 &rcc.ctlr,
                 |w| w.hseon().set_bit(),
                 |r| r.hserdy().bit_is_set(),
-            );
-            clocks.hse = Some(hse.frequency);
+                ClockError::HseTimeout,
+            ) {
+                rcc.ctlr.modify(|_, w| w.hseon().clear_bit());
+                return Err(e);
+            }
+        }
+
+        if self.mux == ClockSrc::Pll {
+            // Disable PLL, PLLMUL, PLLXTPRE, PLLSRC can only be written when PLL is off
+            rcc.ctlr.modify(|_, w| w.pllon().clear_bit());
+
+            match self.pll {
+                PLLSrc::Hsi => {
+                    // HSI is used as PLL source
+                    rcc.cfgr0.modify(|_, w| w.pllsrc().clear_bit());
+                }
+                PLLSrc::Hse => {
+                    // HSE is used as PLL source
+                    rcc.cfgr0.modify(|_, w| w.pllsrc().set_bit());
+                }
+            }
+
+            // Enable PLL
+            if let Err(e) = block_timeout(
+                &rcc.ctlr,
+                |w| w.pllon().set_bit(),
+                |r| r.pllrdy().bit_is_set(),
+                ClockError::PllTimeout,
+            ) {
+                rcc.ctlr
+                    .modify(|_, w| w.pllon().clear_bit().hseon().clear_bit());
+                return Err(e);
+            }
         }
 
-        // Configure HCLK
-        // TODO: ADCPRE
-        rcc.cfgr0
-            .modify(|_, w| w.hpre().variant(self.ahb_pre as u8));
+        // All oscillators are running, nothing can fail from here on
+        hsi::set_trim(rcc, self.hsi_trim);
+
+        // Configure HCLK and the ADC clock
+        rcc.cfgr0.modify(|_, w| {
+            w.hpre()
+                .variant(self.ahb_pre as u8)
+                .adcpre()
+                .variant(self.adc_pre as u8)
+        });
+
+        // Raise the flash latency before raising the system clock
+        flash::set_latency(flash::latency().max(clocks.flash_latency));
 
         // Enable PWR domain
         rcc.apb1pcenr.modify(|_, w| w.pwren().set_bit());
         // Enable editing backup_domain RCC.BDCTLR
         // pwr.ctlr.modify(|_, w| w.dbp().set_bit());
 
-        match (self.mux, self.pll) {
-            (ClockSrc::Hse, _) => {
-                block_clock(&rcc.cfgr0, ClockSrc::Hse);
-                clocks.sysclk = clocks.hse.unwrap();
-            }
-            (ClockSrc::Hsi, _) => {
-                block_clock(&rcc.cfgr0, ClockSrc::Hsi);
-                clocks.sysclk = HSI_FREQUENCY;
-            }
-            (ClockSrc::Pll, src) => {
-                // Disable PLL, PLLMUL, PLLXTPRE, PLLSRC can only be written when PLL is off
-                rcc.ctlr.modify(|_, w| w.pllon().clear_bit());
-
-                match src {
-                    PLLSrc::Hsi => {
-                        // HSI is used as PLL source
-                        rcc.cfgr0.modify(|_, w| w.pllsrc().clear_bit());
-                        clocks.sysclk = HSI_FREQUENCY * 2;
-                    }
-                    PLLSrc::Hse => {
-                        // HSE is used as PLL source
-                        rcc.cfgr0.modify(|_, w| w.pllsrc().set_bit());
-                        clocks.sysclk = clocks.hse.unwrap() * 2;
-                    }
-                }
-                clocks.pllclk = Some(clocks.sysclk);
-
-                // Enable PLL
-                block(
-                    &rcc.ctlr,
-                    |w| w.pllon().set_bit(),
-                    |r| r.pllrdy().bit_is_set(),
-                );
-                block_clock(&rcc.cfgr0, ClockSrc::Pll);
-            }
-        }
+        block_clock(&rcc.cfgr0, self.mux);
 
-        // Calculate AHB and APB speeds
-        clocks.hclk = clocks.sysclk / self.ahb_pre;
+        // Lower the flash latency to what the new system clock needs
+        flash::set_latency(clocks.flash_latency);
+
+        // Monitor the HSE once it is running
+        if self.enable_css {
+            css::set_fallback(Some(self.hsi_fallback()));
+            rcc.ctlr.modify(|_, w| w.csson().set_bit());
+        } else {
+            rcc.ctlr.modify(|_, w| w.csson().clear_bit());
+            css::set_fallback(None);
+        }
 
         // Configure low speed internal RC (128khz)
         if self.enable_lsi {
@@ -346,14 +613,17 @@ impl Config {
         rcc.cfgr0.modify(|_, w| w.mco().variant(self.mco as u8));
 
         // Whats up with this? From 20x hal
-        unsafe {
-            qingke::riscv::asm::delay(16);
-        }
+        qingke::riscv::asm::delay(16);
 
-        clocks
+        Ok(clocks)
     }
 }
 
+/// Scale `freq`, derived from the typical HSI frequency, to the HSI frequency `hsi`
+const fn scale_hsi(freq: Hertz, hsi: Hertz) -> Hertz {
+    Hertz::from_raw((freq.raw() as u64 * hsi.raw() as u64 / HSI_FREQUENCY.raw() as u64) as u32)
+}
+
 /// Frozen clock frequencies
 ///
 /// The existence of this value indicates that the clock configuration can no longer be changed
@@ -361,9 +631,12 @@ impl Config {
 pub struct Clocks {
     pub sysclk: Hertz,
     pub hclk: Hertz,
+    pub adcclk: Hertz,
     pub pllclk: Option<Hertz>,
+    pub hsi: Hertz,
     pub hse: Option<Hertz>,
     pub lsi: Option<Hertz>,
+    pub flash_latency: Latency,
 }
 
 impl Clocks {
@@ -377,6 +650,12 @@ impl Clocks {
         self.sysclk
     }
 
+    /// Returns the frequency of the `HSI`, as measured by [`Rcc::calibrate_hsi`] or the
+    /// typical 24MHz.
+    pub fn hsi(&self) -> Hertz {
+        self.hsi
+    }
+
     /// Returns the frequency of the `HSE` if `Some`, else `None`.
     pub fn hse(&self) -> Option<Hertz> {
         self.hse
@@ -387,20 +666,14 @@ impl Clocks {
         self.lsi
     }
 
+    /// Returns the flash wait states configured for `sysclk`
+    pub fn flash_latency(&self) -> Latency {
+        self.flash_latency
+    }
+
     /// Returns the adc clock frequency
     pub fn adcclk(&self) -> Hertz {
-        const ADCPRESC_TABLE: [u8; 20] = [2, 4, 6, 8, 4, 8, 12, 16, 8, 16, 24, 32, 16, 32, 48, 64, 32, 64, 96, 128];
-
-        let rcc = unsafe { &(*RCC::ptr()) };
-        let mut adcpre = rcc.cfgr0.read().adcpre().bits();
-        adcpre = ((adcpre & 0x18) >> 3) | ((adcpre & 0x7) << 2);
-
-        if (adcpre & 0x13) >= 4 {
-            adcpre -= 12;
-        }
-        let presc = ADCPRESC_TABLE[adcpre as usize] as u32;
-
-        self.hclk / presc
+        self.adcclk
     }
 }
 
@@ -409,9 +682,12 @@ impl Default for Clocks {
         Clocks {
             sysclk: 24.MHz(),
             hclk: 8.MHz(),
+            adcclk: 4.MHz(),
             pllclk: None,
+            hsi: HSI_FREQUENCY,
             hse: None,
             lsi: None,
+            flash_latency: Latency::Ws0,
         }
     }
 }
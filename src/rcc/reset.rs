@@ -0,0 +1,103 @@
+use crate::extend::LKUPRST;
+use crate::flash::{unlock, KEY1, KEY2};
+use crate::pac::{EXTEND, FLASH, RCC};
+use crate::pfic::Pfic;
+
+bitflags::bitflags! {
+    /// Causes of the last reset, from the RSTSCKR flags and the lock-up flag of
+    /// EXTEND_CTR
+    ///
+    /// The flags are only cleared by a power-on reset or by [`ResetReason::clear`], so
+    /// several of them can be set at once.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ResetReason: u32 {
+        /// Core lock-up, see [`lockup`](crate::extend::lockup)
+        ///
+        /// Reported from the LKUPRST flag, this bit is reserved in RSTSCKR.
+        const LockUp = 1 << 25;
+        /// NRST pin
+        const Pin = 1 << 26;
+        /// Power-on or power-down
+        const PowerOn = 1 << 27;
+        /// Software reset through the PFIC
+        const Software = 1 << 28;
+        /// Independent watchdog timeout
+        const IndependentWatchdog = 1 << 29;
+        /// Window watchdog timeout or early feed
+        const WindowWatchdog = 1 << 30;
+        /// Entering standby mode with low-power reset enabled in the option bytes
+        const LowPower = 1 << 31;
+    }
+}
+
+/// Remove reset flags
+const RMVF: u32 = 1 << 24;
+
+/// Start from the bootloader after a software reset, in the flash STATR
+const STATR_BOOT_MODE: u32 = 1 << 14;
+/// BOOT_MODE is write protected
+const STATR_BOOT_LOCK: u32 = 1 << 15;
+
+impl ResetReason {
+    /// Read the reset flags
+    ///
+    /// This does not depend on the clock configuration, so it can be called before or
+    /// after `freeze`.
+    pub fn read() -> Self {
+        // NOTE(unsafe) atomic read with no side effects
+        let rstsckr = unsafe { (*RCC::ptr()).rstsckr.read().bits() };
+        let mut reason = Self::from_bits_truncate(rstsckr) - Self::LockUp;
+
+        // NOTE(unsafe) atomic read with no side effects
+        let extend_ctr = unsafe { (*EXTEND::ptr()).extend_ctr.read().bits() };
+        reason.set(Self::LockUp, extend_ctr & LKUPRST != 0);
+        reason
+    }
+
+    /// Read the reset flags and clear them, so the next reset reports only its own cause
+    pub fn take() -> Self {
+        let reason = Self::read();
+        Self::clear();
+        reason
+    }
+
+    /// Clear all reset flags
+    pub fn clear() {
+        // NOTE(unsafe) RMVF only clears the flags, LSION is preserved by the modify
+        unsafe { (*RCC::ptr()).rstsckr.modify(|r, w| w.bits(r.bits() | RMVF)) };
+
+        // LKUPRST is cleared by writing 1, the other bits are written back unchanged.
+        // NOTE(unsafe) `Extend` only modifies EXTEND_CTR in critical sections as well
+        critical_section::with(|_| unsafe {
+            (*EXTEND::ptr())
+                .extend_ctr
+                .modify(|r, w| w.bits(r.bits() | LKUPRST))
+        });
+    }
+}
+
+/// Reset the core and all peripherals through the PFIC
+///
+/// The reset is reported as [`ResetReason::Software`].
+pub fn system_reset() -> ! {
+    Pfic::system_reset()
+}
+
+/// Reset into the built-in bootloader
+///
+/// The bootloader starts the user code again on its next reset, or when told so by
+/// the programming tool.
+pub fn reset_into_bootloader() -> ! {
+    // NOTE(unsafe) the chip is reset right after, nothing else uses the flash controller
+    let flash = unsafe { &*FLASH::ptr() };
+    // BOOT_MODEKEYR only accepts its keys once the controller is unlocked
+    unlock(flash);
+    flash.boot_modekeyp.write(|w| unsafe { w.bits(KEY1) });
+    flash.boot_modekeyp.write(|w| unsafe { w.bits(KEY2) });
+    // BOOT_LOCK is cleared by the key sequence, writing it back as 0 keeps it that way
+    flash
+        .statr
+        .modify(|r, w| unsafe { w.bits((r.bits() & !STATR_BOOT_LOCK) | STATR_BOOT_MODE) });
+
+    system_reset()
+}
@@ -1,9 +1,13 @@
 //! Universal Synchronous Asynchronous Receiver Transmitter (USART)
 
-use crate::pac::{AFIO, USART1};
+use crate::dma::{self, dma1, CircBuffer, DataSize, Direction, RxDma, Transfer, TxDma, R, W};
+use crate::pac::{usart1, AFIO, USART1};
 use crate::rcc::{BusClock, Clocks, Enable, Rcc, Reset};
 use core::convert::Infallible;
 use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::{self, Ordering};
+use embedded_dma::{ReadBuffer, WriteBuffer};
 use embedded_hal_02::serial::{Read, Write};
 
 pub trait Ck<const REMAP: u8> {
@@ -110,8 +114,43 @@ impl<CK, TX, RX, CTS, RTS> Usart<CK, TX, RX, CTS, RTS> {
     pub fn free(self) -> (CK, TX, RX, CTS, RTS, USART1) {
         (self.ck, self.tx, self.rx, self.cts, self.rts, self.usart)
     }
+
+    /// Split the USART into its transmitting and receiving halves
+    ///
+    /// The pins stay configured and the peripheral stays enabled.
+    pub fn split(self) -> (UsartTx, UsartRx) {
+        (
+            UsartTx {
+                _usart: PhantomData,
+            },
+            UsartRx {
+                _usart: PhantomData,
+            },
+        )
+    }
+
+    /// Split the USART and hand its halves to their DMA1 channels
+    ///
+    /// USART1 TX requests are served by channel 4 and RX requests by channel 5.
+    pub fn with_dma(self, tx_channel: dma1::C4, rx_channel: dma1::C5) -> (UsartTxDma, UsartRxDma) {
+        let (tx, rx) = self.split();
+        (tx.with_dma(tx_channel), rx.with_dma(rx_channel))
+    }
+}
+
+/// Transmitting half of a [`Usart`]
+pub struct UsartTx {
+    _usart: PhantomData<USART1>,
+}
+
+/// Receiving half of a [`Usart`]
+pub struct UsartRx {
+    _usart: PhantomData<USART1>,
 }
 
+pub type UsartTxDma = TxDma<UsartTx, dma1::C4>;
+pub type UsartRxDma = RxDma<UsartRx, dma1::C5>;
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum DataBits {
     DataBits8,
@@ -185,7 +224,7 @@ impl UsartExt for USART1 {
 
         AFIO::enable(&mut rcc.apb2);
 
-        let apbclk = USART1::clock(&clocks).raw();
+        let apbclk = USART1::clock(clocks).raw();
         let integer_divider = (25 * apbclk) / (4 * config.baudrate);
         let div_m = integer_divider / 100;
         let div_f = integer_divider - 100 * div_m;
@@ -249,52 +288,64 @@ impl<CK, TX, RX, CTS, RTS> Usart<CK, TX, RX, CTS, RTS> {
     }
 
     pub fn write_u16(&mut self, word: u16) -> nb::Result<(), Infallible> {
-        if self.usart.statr.read().txe().bit_is_set() {
-            self.usart.datar.write(|w| w.dr().variant(word));
-            Ok(())
-        } else {
-            Err(nb::Error::WouldBlock)
-        }
+        write_u16(&self.usart, word)
     }
 
     pub fn flush(&mut self) -> nb::Result<(), Infallible> {
-        if self.usart.statr.read().tc().bit_is_set() {
-            Ok(())
-        } else {
-            Err(nb::Error::WouldBlock)
-        }
+        flush(&self.usart)
     }
 
     pub fn read_u16(&mut self) -> nb::Result<u16, Error> {
-        let statr = self.usart.statr.read();
-
-        // Check for any errors
-        let err = if statr.pe().bit_is_set() {
-            Some(Error::Parity)
-        } else if statr.fe().bit_is_set() {
-            Some(Error::Framing)
-        } else if statr.ne().bit_is_set() {
-            Some(Error::Noise)
-        } else if statr.ore().bit_is_set() {
-            Some(Error::Overrun)
-        } else {
-            None
-        };
+        read_u16(&self.usart)
+    }
+}
 
-        if let Some(err) = err {
-            // Some error occurred. In order to clear that error flag, you have to
-            // do a read from the statr register followed by a read from the datar register.
-            let _ = self.usart.statr.read();
-            let _ = self.usart.datar.read();
-            Err(nb::Error::Other(err))
+fn write_u16(usart: &usart1::RegisterBlock, word: u16) -> nb::Result<(), Infallible> {
+    if usart.statr.read().txe().bit_is_set() {
+        usart.datar.write(|w| w.dr().variant(word));
+        Ok(())
+    } else {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+fn flush(usart: &usart1::RegisterBlock) -> nb::Result<(), Infallible> {
+    if usart.statr.read().tc().bit_is_set() {
+        Ok(())
+    } else {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+fn read_u16(usart: &usart1::RegisterBlock) -> nb::Result<u16, Error> {
+    let statr = usart.statr.read();
+
+    // Check for any errors
+    let err = if statr.pe().bit_is_set() {
+        Some(Error::Parity)
+    } else if statr.fe().bit_is_set() {
+        Some(Error::Framing)
+    } else if statr.ne().bit_is_set() {
+        Some(Error::Noise)
+    } else if statr.ore().bit_is_set() {
+        Some(Error::Overrun)
+    } else {
+        None
+    };
+
+    if let Some(err) = err {
+        // Some error occurred. In order to clear that error flag, you have to
+        // do a read from the statr register followed by a read from the datar register.
+        let _ = usart.statr.read();
+        let _ = usart.datar.read();
+        Err(nb::Error::Other(err))
+    } else {
+        // Check if a byte is available
+        if statr.rxne().bit_is_set() {
+            // Read the received byte
+            Ok(usart.datar.read().dr().bits())
         } else {
-            // Check if a byte is available
-            if statr.rxne().bit_is_set() {
-                // Read the received byte
-                Ok(self.usart.datar.read().dr().bits())
-            } else {
-                Err(nb::Error::WouldBlock)
-            }
+            Err(nb::Error::WouldBlock)
         }
     }
 }
@@ -351,3 +402,339 @@ impl<CK, TX, RX, CTS, RTS> Read<u16> for Usart<CK, TX, RX, CTS, RTS> {
         self.read_u16()
     }
 }
+
+impl UsartTx {
+    fn usart(&self) -> &usart1::RegisterBlock {
+        // NOTE(unsafe) the transmitter only touches DATAR writes, STATR reads and DMAT
+        unsafe { &(*USART1::ptr()) }
+    }
+
+    pub fn write_u16(&mut self, word: u16) -> nb::Result<(), Infallible> {
+        write_u16(self.usart(), word)
+    }
+
+    pub fn flush(&mut self) -> nb::Result<(), Infallible> {
+        flush(self.usart())
+    }
+
+    /// Transmit through DMA1 channel 4
+    pub fn with_dma(self, channel: dma1::C4) -> UsartTxDma {
+        self.usart().ctlr3.modify(|_, w| w.dmat().set_bit());
+        TxDma {
+            payload: self,
+            channel,
+        }
+    }
+}
+
+impl UsartRx {
+    fn usart(&self) -> &usart1::RegisterBlock {
+        // NOTE(unsafe) the receiver only touches DATAR and STATR reads and DMAR
+        unsafe { &(*USART1::ptr()) }
+    }
+
+    pub fn read_u16(&mut self) -> nb::Result<u16, Error> {
+        read_u16(self.usart())
+    }
+
+    /// Receive through DMA1 channel 5
+    pub fn with_dma(self, channel: dma1::C5) -> UsartRxDma {
+        self.usart().ctlr3.modify(|_, w| w.dmar().set_bit());
+        RxDma {
+            payload: self,
+            channel,
+        }
+    }
+}
+
+impl Write<u8> for UsartTx {
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.write_u16(word as u16)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.flush()
+    }
+}
+
+impl Read<u8> for UsartRx {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.read_u16().map(|word16| word16 as u8)
+    }
+}
+
+impl core::fmt::Write for UsartTx {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        (self as &mut dyn embedded_hal_02::serial::Write<u8, Error = _>).write_str(s)
+    }
+}
+
+/// Address of the data register, used as the peripheral side of DMA transfers
+fn datar_address() -> u32 {
+    unsafe { &(*USART1::ptr()).datar as *const _ as u32 }
+}
+
+impl UsartTxDma {
+    /// Stop DMA requests and give back the transmitter and its channel
+    pub fn release(mut self) -> (UsartTx, dma1::C4) {
+        dma::TransferPayload::stop(&mut self);
+        self.payload
+            .usart()
+            .ctlr3
+            .modify(|_, w| w.dmat().clear_bit());
+        let TxDma { payload, channel } = self;
+        (payload, channel)
+    }
+}
+
+impl UsartRxDma {
+    /// Stop DMA requests and give back the receiver and its channel
+    pub fn release(mut self) -> (UsartRx, dma1::C5) {
+        dma::TransferPayload::stop(&mut self);
+        self.payload
+            .usart()
+            .ctlr3
+            .modify(|_, w| w.dmar().clear_bit());
+        let RxDma { payload, channel } = self;
+        (payload, channel)
+    }
+
+    /// Continuously receive into `buffer`, restarting from its beginning when it is full
+    ///
+    /// Unlike [`CircReadDma::circ_read`](dma::CircReadDma::circ_read) the received bytes
+    /// are not handed out by halves but as soon as they arrive, see [`RxRing::read`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer` is empty.
+    pub fn ring<B>(mut self, mut buffer: B) -> RxRing<B>
+    where
+        B: WriteBuffer<Word = u8>,
+    {
+        // NOTE(unsafe) We own the buffer now and we won't call other `&mut` on it
+        // until the end of the transfer.
+        let (ptr, len) = unsafe { buffer.write_buffer() };
+        assert!(len > 0);
+        self.configure(ptr as u32, len, true);
+        dma::TransferPayload::start(&mut self);
+
+        RxRing {
+            buffer,
+            payload: self,
+            read_pos: 0,
+            write_pos: 0,
+        }
+    }
+
+    fn configure(&mut self, address: u32, len: usize, circular: bool) {
+        self.channel.set_peripheral_address(datar_address(), false);
+        self.channel.set_memory_address(address, true);
+        self.channel.set_transfer_length(len);
+
+        atomic::compiler_fence(Ordering::Release);
+
+        self.channel.set_mem2mem(false);
+        self.channel.set_priority(dma::Priority::Medium);
+        self.channel.set_data_size(DataSize::Bits8, DataSize::Bits8);
+        self.channel.set_circular(circular);
+        self.channel.set_direction(Direction::PeripheralToMemory);
+    }
+}
+
+impl dma::TransferPayload for UsartTxDma {
+    fn start(&mut self) {
+        self.channel.start();
+    }
+
+    fn stop(&mut self) {
+        self.channel.stop();
+    }
+}
+
+impl dma::TransferPayload for UsartRxDma {
+    fn start(&mut self) {
+        self.channel.start();
+    }
+
+    fn stop(&mut self) {
+        self.channel.stop();
+    }
+}
+
+impl dma::Transmit for UsartTxDma {
+    type TxChannel = dma1::C4;
+    type ReceivedWord = u8;
+}
+
+impl dma::Receive for UsartRxDma {
+    type RxChannel = dma1::C5;
+    type TransmittedWord = u8;
+}
+
+impl<B> dma::WriteDma<B, u8> for UsartTxDma
+where
+    B: ReadBuffer<Word = u8>,
+{
+    fn write(mut self, buffer: B) -> Transfer<R, B, Self> {
+        // NOTE(unsafe) We own the buffer now and we won't call other `&mut` on it
+        // until the end of the transfer.
+        let (ptr, len) = unsafe { buffer.read_buffer() };
+        self.channel.set_peripheral_address(datar_address(), false);
+        self.channel.set_memory_address(ptr as u32, true);
+        self.channel.set_transfer_length(len);
+
+        atomic::compiler_fence(Ordering::Release);
+
+        self.channel.set_mem2mem(false);
+        self.channel.set_priority(dma::Priority::Medium);
+        self.channel.set_data_size(DataSize::Bits8, DataSize::Bits8);
+        self.channel.set_circular(false);
+        self.channel.set_direction(Direction::MemoryToPeripheral);
+
+        dma::TransferPayload::start(&mut self);
+
+        Transfer::r(buffer, self)
+    }
+}
+
+impl<B> dma::ReadDma<B, u8> for UsartRxDma
+where
+    B: WriteBuffer<Word = u8>,
+{
+    fn read(mut self, mut buffer: B) -> Transfer<W, B, Self> {
+        // NOTE(unsafe) We own the buffer now and we won't call other `&mut` on it
+        // until the end of the transfer.
+        let (ptr, len) = unsafe { buffer.write_buffer() };
+        self.configure(ptr as u32, len, false);
+        dma::TransferPayload::start(&mut self);
+
+        Transfer::w(buffer, self)
+    }
+}
+
+impl<B> dma::CircReadDma<B, u8> for UsartRxDma
+where
+    &'static mut [B; 2]: WriteBuffer<Word = u8>,
+    B: 'static,
+{
+    fn circ_read(mut self, mut buffer: &'static mut [B; 2]) -> CircBuffer<B, Self> {
+        // NOTE(unsafe) We own the buffer now and we won't call other `&mut` on it
+        // until the end of the transfer.
+        let (ptr, len) = unsafe { buffer.write_buffer() };
+        self.configure(ptr as u32, len, true);
+        dma::TransferPayload::start(&mut self);
+
+        CircBuffer::new(buffer, self)
+    }
+}
+
+/// Circular reception that hands out bytes as soon as they were received
+///
+/// The DMA does not wait for the buffer to be read: bytes that are not read within one
+/// buffer length of reception are overwritten. [`RxRing::read`] detects this as long
+/// as less than one and a half buffer lengths are received between two calls.
+pub struct RxRing<B> {
+    buffer: B,
+    payload: UsartRxDma,
+    read_pos: usize,
+    /// Position of the DMA at the previous read
+    write_pos: usize,
+}
+
+impl<B> RxRing<B>
+where
+    B: WriteBuffer<Word = u8>,
+{
+    /// Copy the bytes received since the previous call into `buf`
+    ///
+    /// Returns the number of bytes copied, which is 0 if nothing new was received.
+    /// If the DMA overwrote bytes that were not read yet, the unread bytes are dropped
+    /// and [`dma::Error::Overrun`] is returned.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, dma::Error> {
+        // NOTE(unsafe) only the part of the buffer already written by the DMA is read
+        let (ptr, len) = unsafe { self.buffer.write_buffer() };
+        let channel = &mut self.payload.channel;
+
+        // The flags tell whether the DMA passed the middle or the end of the buffer since
+        // the previous read, they must not change while the position is read
+        let (write_pos, half, complete) = loop {
+            let half = channel.is_half_transfer();
+            let complete = channel.is_transfer_complete();
+            let write_pos = (len - channel.get_ndtr() as usize) % len;
+            if half == channel.is_half_transfer() && complete == channel.is_transfer_complete() {
+                break (write_pos, half, complete);
+            }
+        };
+        if half {
+            channel.clear_event(dma::Event::HalfTransfer);
+        }
+        if complete {
+            channel.clear_event(dma::Event::TransferComplete);
+        }
+
+        atomic::compiler_fence(Ordering::Acquire);
+
+        // Find out how far the DMA moved, the flags tell apart a full lap
+        let last_pos = core::mem::replace(&mut self.write_pos, write_pos);
+        let half_pos = len - len / 2;
+        let lapped = if !complete {
+            false
+        } else if write_pos >= last_pos {
+            true
+        } else {
+            // Wrapped around once, the middle is only passed if it is on the way
+            half && last_pos >= half_pos && write_pos < half_pos
+        };
+        let unread = (last_pos + len - self.read_pos) % len;
+        let received = (write_pos + len - last_pos) % len;
+
+        if lapped || unread + received >= len {
+            self.read_pos = write_pos;
+            return Err(dma::Error::Overrun);
+        }
+
+        let mut copied = 0;
+        while copied < buf.len() && self.read_pos != write_pos {
+            // NOTE(unsafe) read_pos is always within the buffer
+            buf[copied] = unsafe { core::ptr::read_volatile(ptr.add(self.read_pos)) };
+            self.read_pos = (self.read_pos + 1) % len;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    /// Access the DMA channel, e.g. to listen for half/complete transfer events
+    ///
+    /// The half/complete transfer flags are cleared by [`RxRing::read`], which uses
+    /// them to detect overruns, do not clear them through the channel.
+    pub fn channel(&mut self) -> &mut dma1::C5 {
+        &mut self.payload.channel
+    }
+
+    /// Stop the reception and give back the buffer and the receiver
+    pub fn stop(mut self) -> (B, UsartRxDma) {
+        dma::TransferPayload::stop(&mut self.payload);
+
+        atomic::compiler_fence(Ordering::Acquire);
+
+        // NOTE(unsafe) `RxRing` stops the DMA on drop, so the fields are moved out with
+        // `ptr::read` and `self` is forgotten. There is no panic branch in between.
+        unsafe {
+            let buffer = core::ptr::read(&self.buffer);
+            let payload = core::ptr::read(&self.payload);
+            core::mem::forget(self);
+            (buffer, payload)
+        }
+    }
+}
+
+impl<B> Drop for RxRing<B> {
+    fn drop(&mut self) {
+        dma::TransferPayload::stop(&mut self.payload);
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
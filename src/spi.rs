@@ -0,0 +1,918 @@
+//! Serial Peripheral Interface (SPI)
+//!
+//! ## Pin mapping
+//!
+//! | Signal | SPI1_RM = 0 | SPI1_RM = 1 |
+//! |:------:|:-----------:|:-----------:|
+//! |  NSS   |     PC1     |     PC0     |
+//! |  SCK   |     PC5     |     PC5     |
+//! |  MISO  |     PC7     |     PC7     |
+//! |  MOSI  |     PC6     |     PC6     |
+//!
+//! The remap only moves the NSS signal, so in master mode (where NSS is managed in
+//! software) both options use the same pins. [`SpiSlave`] uses the hardware NSS input
+//! and selects the remap from the NSS pin that is passed in.
+//!
+//! ## 3-wire mode
+//!
+//! [`SpiBidi`] uses the bidirectional data mode of the peripheral, where MOSI is the
+//! only data line and its direction is switched between writes and reads.
+
+use fugit::HertzU32;
+
+use crate::{
+    gpio::*,
+    pac::{AFIO, SPI1},
+    rcc::{BusClock, Clocks, Enable, Rcc, Reset},
+};
+
+pub use embedded_hal_1::spi::{Mode, Phase, Polarity, MODE_0, MODE_1, MODE_2, MODE_3};
+
+/// Ready to use SPI peripheral in master mode
+///
+/// `W` is the data frame size, either `u8` or `u16`.
+pub struct Spi<SCK, MISO, MOSI, W = u8> {
+    spi: SPI1,
+    sck: SCK,
+    miso: MISO,
+    mosi: MOSI,
+    _word: core::marker::PhantomData<W>,
+}
+
+/// Order in which the bits of a frame are shifted out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    /// Most significant bit first
+    MsbFirst,
+    /// Least significant bit first
+    LsbFirst,
+}
+
+/// SPI peripheral configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpiConfig {
+    /// Clock polarity and phase
+    pub mode: Mode,
+    /// Bit order of each frame
+    pub bit_order: BitOrder,
+    /// Requested SCK frequency. The closest prescaler that does not exceed it is chosen.
+    pub frequency: HertzU32,
+}
+
+/// Mode 0, MSB first, 1 MHz
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            mode: MODE_0,
+            bit_order: BitOrder::MsbFirst,
+            frequency: HertzU32::MHz(1),
+        }
+    }
+}
+
+/// SPI error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// Received data was overwritten before it was read
+    Overrun,
+    /// NSS was pulled low while in master mode
+    ModeFault,
+    /// CRC check failed
+    Crc,
+}
+
+impl embedded_hal_1::spi::Error for Error {
+    fn kind(&self) -> embedded_hal_1::spi::ErrorKind {
+        use embedded_hal_1::spi::ErrorKind;
+        match self {
+            Error::Overrun => ErrorKind::Overrun,
+            Error::ModeFault => ErrorKind::ModeFault,
+            Error::Crc => ErrorKind::Other,
+        }
+    }
+}
+
+/// Data frame size (type state)
+///
+/// It can not be implemented by outside types.
+pub trait FrameSize: Copy + Default + 'static + crate::Sealed {
+    /// Value of the `DFF` bit in `CTLR1`
+    #[doc(hidden)]
+    const DFF: bool;
+
+    #[doc(hidden)]
+    fn from_data(data: u16) -> Self;
+
+    #[doc(hidden)]
+    fn into_data(self) -> u16;
+}
+
+impl crate::Sealed for u8 {}
+impl FrameSize for u8 {
+    const DFF: bool = false;
+
+    #[inline(always)]
+    fn from_data(data: u16) -> Self {
+        data as u8
+    }
+
+    #[inline(always)]
+    fn into_data(self) -> u16 {
+        self as u16
+    }
+}
+
+impl crate::Sealed for u16 {}
+impl FrameSize for u16 {
+    const DFF: bool = true;
+
+    #[inline(always)]
+    fn from_data(data: u16) -> Self {
+        data
+    }
+
+    #[inline(always)]
+    fn into_data(self) -> u16 {
+        self
+    }
+}
+
+/// Calculate the baud rate prescaler bits (`BR`) for the requested frequency.
+///
+/// SCK is `fPCLK / 2^(BR + 1)`, the fastest rate not exceeding `freq` is selected.
+fn baud_rate_prescaler(clock: HertzU32, freq: HertzU32) -> u8 {
+    let mut br = 0;
+    while br < 0b111 && clock.raw() / (2 << br) > freq.raw() {
+        br += 1;
+    }
+    br
+}
+
+/// Power up SPI1 and configure it as a master
+fn setup_master(
+    spi: &SPI1,
+    remap: bool,
+    bidi: bool,
+    config: SpiConfig,
+    rcc: &mut Rcc,
+    clocks: &Clocks,
+) {
+    // Ensure spi is enabled and reset to known state
+    SPI1::enable(&mut rcc.apb2);
+    SPI1::reset(&mut rcc.apb2);
+
+    AFIO::enable(&mut rcc.apb2);
+
+    // Configure the remap bit in AFIO to match our pin selection
+    unsafe {
+        (*AFIO::ptr()).pcfr.modify(|_, w| w.spi1rm().bit(remap));
+    }
+
+    let br = baud_rate_prescaler(SPI1::clock(clocks), config.frequency);
+
+    spi.ctlr1.write(|w| {
+        w.cpha()
+            .bit(config.mode.phase == Phase::CaptureOnSecondTransition)
+            .cpol()
+            .bit(config.mode.polarity == Polarity::IdleHigh)
+            .mstr() // Master mode
+            .set_bit()
+            .br()
+            .variant(br)
+            .lsbfirst()
+            .bit(config.bit_order == BitOrder::LsbFirst)
+            .ssm() // NSS is managed in software and held high
+            .set_bit()
+            .ssi()
+            .set_bit()
+            .dff() // 8-bit frames
+            .clear_bit()
+            .bidimode() // Single data line, starting out as an output
+            .bit(bidi)
+            .bidioe()
+            .bit(bidi)
+    });
+
+    // Start peripheral
+    spi.ctlr1.modify(|_, w| w.spe().set_bit());
+}
+
+/// Change the data frame size, which is only allowed while the peripheral is disabled
+fn set_frame_size<W: FrameSize>(spi: &SPI1) {
+    while spi.statr.read().bsy().bit_is_set() {}
+    spi.ctlr1.modify(|_, w| w.spe().clear_bit());
+    spi.ctlr1.modify(|_, w| w.dff().bit(W::DFF));
+    spi.ctlr1.modify(|_, w| w.spe().set_bit());
+}
+
+/// Check the master mode error flags, clearing them if set
+fn check_master_error(spi: &SPI1) -> Result<(), Error> {
+    let statr = spi.statr.read();
+
+    if statr.ovr().bit_is_set() {
+        // Overrun is cleared by reading DATAR followed by STATR
+        let _ = spi.datar.read();
+        let _ = spi.statr.read();
+        Err(Error::Overrun)
+    } else if statr.modf().bit_is_set() {
+        // Mode fault is cleared by reading STATR followed by a write to CTLR1.
+        // The hardware also clears SPE and MSTR, so restore them
+        spi.ctlr1.modify(|_, w| w.mstr().set_bit().spe().set_bit());
+        Err(Error::ModeFault)
+    } else if statr.crcerr().bit_is_set() {
+        spi.statr.modify(|_, w| w.crcerr().clear_bit());
+        Err(Error::Crc)
+    } else {
+        Ok(())
+    }
+}
+
+impl<SCK, MISO, MOSI> Spi<SCK, MISO, MOSI>
+where
+    (SCK, MISO, MOSI): Spi1Pins,
+{
+    /// Initialise the SPI1 peripheral in master mode with valid SCK, MISO and MOSI pins
+    ///
+    /// Use [`NoPin`] in place of MISO or MOSI for a transmit or receive only bus.
+    /// Frames are 8 bits wide, see [`Spi::frame_size_16bit`] for 16-bit frames.
+    pub fn spi1(
+        spi: SPI1,
+        sck: SCK,
+        miso: MISO,
+        mosi: MOSI,
+        config: SpiConfig,
+        rcc: &mut Rcc,
+        clocks: &Clocks,
+    ) -> Self {
+        setup_master(
+            &spi,
+            <(SCK, MISO, MOSI) as Spi1Pins>::REMAP,
+            false,
+            config,
+            rcc,
+            clocks,
+        );
+
+        Self {
+            spi,
+            sck,
+            miso,
+            mosi,
+            _word: core::marker::PhantomData,
+        }
+    }
+
+    /// Switch to 16-bit data frames
+    pub fn frame_size_16bit(self) -> Spi<SCK, MISO, MOSI, u16> {
+        set_frame_size::<u16>(&self.spi);
+
+        Spi {
+            spi: self.spi,
+            sck: self.sck,
+            miso: self.miso,
+            mosi: self.mosi,
+            _word: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<SCK, MISO, MOSI> Spi<SCK, MISO, MOSI, u16>
+where
+    (SCK, MISO, MOSI): Spi1Pins,
+{
+    /// Switch to 8-bit data frames
+    pub fn frame_size_8bit(self) -> Spi<SCK, MISO, MOSI, u8> {
+        set_frame_size::<u8>(&self.spi);
+
+        Spi {
+            spi: self.spi,
+            sck: self.sck,
+            miso: self.miso,
+            mosi: self.mosi,
+            _word: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<SCK, MISO, MOSI, W: FrameSize> Spi<SCK, MISO, MOSI, W>
+where
+    (SCK, MISO, MOSI): Spi1Pins,
+{
+    /// Deconstruct the SPI peripheral and return it's raw hardware resources
+    pub fn release(self) -> (SPI1, SCK, MISO, MOSI) {
+        // Wait for the last frame to leave the shift register before disabling
+        while self.spi.statr.read().bsy().bit_is_set() {}
+        self.spi.ctlr1.modify(|_, w| w.spe().clear_bit());
+
+        (self.spi, self.sck, self.miso, self.mosi)
+    }
+
+    /// Returns true if the peripheral is busy communicating
+    pub fn is_busy(&self) -> bool {
+        self.spi.statr.read().bsy().bit_is_set()
+    }
+
+    /// Try to read a received frame, checking for errors first
+    fn nb_read(&mut self) -> nb::Result<W, Error> {
+        check_master_error(&self.spi)?;
+
+        if self.spi.statr.read().rxne().bit_is_set() {
+            Ok(W::from_data(self.spi.datar.read().datar().bits()))
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Try to queue a frame for transmission
+    fn nb_send(&mut self, word: W) -> nb::Result<(), Error> {
+        check_master_error(&self.spi)?;
+
+        if self.spi.statr.read().txe().bit_is_set() {
+            self.spi
+                .datar
+                .write(|w| w.datar().variant(word.into_data()));
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Send one frame and return the frame that was clocked in at the same time
+    fn transfer_word(&mut self, word: W) -> Result<W, Error> {
+        nb::block!(self.nb_send(word))?;
+        nb::block!(self.nb_read())
+    }
+}
+
+impl<SCK, MISO, MOSI, W> embedded_hal_1::spi::ErrorType for Spi<SCK, MISO, MOSI, W> {
+    type Error = Error;
+}
+
+impl<SCK, MISO, MOSI, W: FrameSize> embedded_hal_1::spi::SpiBus<W> for Spi<SCK, MISO, MOSI, W>
+where
+    (SCK, MISO, MOSI): Spi1Pins,
+{
+    fn read(&mut self, words: &mut [W]) -> Result<(), Self::Error> {
+        for word in words {
+            *word = self.transfer_word(W::default())?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[W]) -> Result<(), Self::Error> {
+        for word in words {
+            self.transfer_word(*word)?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [W], write: &[W]) -> Result<(), Self::Error> {
+        let len = read.len().max(write.len());
+        for i in 0..len {
+            let received = self.transfer_word(write.get(i).copied().unwrap_or_default())?;
+            if let Some(word) = read.get_mut(i) {
+                *word = received;
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [W]) -> Result<(), Self::Error> {
+        for word in words {
+            *word = self.transfer_word(*word)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        while self.is_busy() {}
+        Ok(())
+    }
+}
+
+impl<SCK, MISO, MOSI, W: FrameSize> embedded_hal_02::spi::FullDuplex<W> for Spi<SCK, MISO, MOSI, W>
+where
+    (SCK, MISO, MOSI): Spi1Pins,
+{
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<W, Self::Error> {
+        self.nb_read()
+    }
+
+    fn send(&mut self, word: W) -> nb::Result<(), Self::Error> {
+        self.nb_send(word)
+    }
+}
+
+impl<SCK, MISO, MOSI, W: FrameSize> embedded_hal_02::blocking::spi::transfer::Default<W>
+    for Spi<SCK, MISO, MOSI, W>
+where
+    (SCK, MISO, MOSI): Spi1Pins,
+{
+}
+
+impl<SCK, MISO, MOSI, W: FrameSize> embedded_hal_02::blocking::spi::write::Default<W>
+    for Spi<SCK, MISO, MOSI, W>
+where
+    (SCK, MISO, MOSI): Spi1Pins,
+{
+}
+
+impl<SCK, MISO, MOSI, W: FrameSize> embedded_hal_02::blocking::spi::write_iter::Default<W>
+    for Spi<SCK, MISO, MOSI, W>
+where
+    (SCK, MISO, MOSI): Spi1Pins,
+{
+}
+
+/// Ready to use SPI peripheral in 3-wire half-duplex master mode
+///
+/// MOSI is the only data line, its direction is switched by the `BIDIOE` bit.
+/// `W` is the data frame size, either `u8` or `u16`.
+pub struct SpiBidi<SCK, MOSI, W = u8> {
+    spi: SPI1,
+    sck: SCK,
+    mosi: MOSI,
+    _word: core::marker::PhantomData<W>,
+}
+
+impl<SCK, MOSI> SpiBidi<SCK, MOSI>
+where
+    (SCK, NoPin, MOSI): Spi1Pins,
+{
+    /// Initialise the SPI1 peripheral in bidirectional master mode with valid SCK and MOSI pins
+    ///
+    /// Frames are 8 bits wide, see [`SpiBidi::frame_size_16bit`] for 16-bit frames.
+    pub fn spi1(
+        spi: SPI1,
+        sck: SCK,
+        mosi: MOSI,
+        config: SpiConfig,
+        rcc: &mut Rcc,
+        clocks: &Clocks,
+    ) -> Self {
+        setup_master(
+            &spi,
+            <(SCK, NoPin, MOSI) as Spi1Pins>::REMAP,
+            true,
+            config,
+            rcc,
+            clocks,
+        );
+
+        Self {
+            spi,
+            sck,
+            mosi,
+            _word: core::marker::PhantomData,
+        }
+    }
+
+    /// Switch to 16-bit data frames
+    pub fn frame_size_16bit(self) -> SpiBidi<SCK, MOSI, u16> {
+        set_frame_size::<u16>(&self.spi);
+
+        SpiBidi {
+            spi: self.spi,
+            sck: self.sck,
+            mosi: self.mosi,
+            _word: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<SCK, MOSI> SpiBidi<SCK, MOSI, u16>
+where
+    (SCK, NoPin, MOSI): Spi1Pins,
+{
+    /// Switch to 8-bit data frames
+    pub fn frame_size_8bit(self) -> SpiBidi<SCK, MOSI, u8> {
+        set_frame_size::<u8>(&self.spi);
+
+        SpiBidi {
+            spi: self.spi,
+            sck: self.sck,
+            mosi: self.mosi,
+            _word: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<SCK, MOSI, W: FrameSize> SpiBidi<SCK, MOSI, W>
+where
+    (SCK, NoPin, MOSI): Spi1Pins,
+{
+    /// Deconstruct the SPI peripheral and return it's raw hardware resources
+    pub fn release(self) -> (SPI1, SCK, MOSI) {
+        while self.spi.statr.read().bsy().bit_is_set() {}
+        self.spi.ctlr1.modify(|_, w| w.spe().clear_bit());
+
+        (self.spi, self.sck, self.mosi)
+    }
+
+    /// Returns true if the peripheral is busy communicating
+    pub fn is_busy(&self) -> bool {
+        self.spi.statr.read().bsy().bit_is_set()
+    }
+
+    /// Drive the data line and send `words`, blocking until the last frame is out
+    pub fn write(&mut self, words: &[W]) -> Result<(), Error> {
+        // Output direction, the peripheral only clocks while there is data to send
+        self.spi.ctlr1.modify(|_, w| w.bidioe().set_bit());
+
+        for word in words {
+            check_master_error(&self.spi)?;
+            while self.spi.statr.read().txe().bit_is_clear() {}
+            self.spi
+                .datar
+                .write(|w| w.datar().variant(word.into_data()));
+        }
+
+        while self.spi.statr.read().txe().bit_is_clear() {}
+        while self.is_busy() {}
+
+        check_master_error(&self.spi)
+    }
+
+    /// Release the data line and clock in `words.len()` frames
+    pub fn read(&mut self, words: &mut [W]) -> Result<(), Error> {
+        let Some((last, rest)) = words.split_last_mut() else {
+            return Ok(());
+        };
+
+        // Drop any stale data before switching direction
+        while self.is_busy() {}
+        self.spi.ctlr1.modify(|_, w| w.spe().clear_bit());
+        let _ = self.spi.datar.read();
+        self.spi.ctlr1.modify(|_, w| w.bidioe().clear_bit());
+
+        // One SCK period in core cycles, the delay loop takes at least one cycle per
+        // iteration
+        let sck_period = 2 << self.spi.ctlr1.read().br().bits();
+
+        // In input direction SCK runs continuously as long as SPE is set. The clock is
+        // stopped by clearing SPE while the last frame is being received, so this must
+        // not be interrupted.
+        critical_section::with(|_| {
+            self.spi.ctlr1.modify(|_, w| w.spe().set_bit());
+
+            if rest.is_empty() {
+                qingke::riscv::asm::delay(sck_period);
+                self.spi.ctlr1.modify(|_, w| w.spe().clear_bit());
+            }
+
+            let n = rest.len();
+            for (i, word) in rest.iter_mut().enumerate() {
+                while self.spi.statr.read().rxne().bit_is_clear() {}
+                if i + 1 == n {
+                    // Let the last frame start before stopping the clock
+                    qingke::riscv::asm::delay(sck_period);
+                    self.spi.ctlr1.modify(|_, w| w.spe().clear_bit());
+                }
+                *word = W::from_data(self.spi.datar.read().datar().bits());
+            }
+
+            while self.spi.statr.read().rxne().bit_is_clear() {}
+            *last = W::from_data(self.spi.datar.read().datar().bits());
+        });
+
+        let result = check_master_error(&self.spi);
+
+        // Park in output direction so enabling the peripheral does not start the clock
+        self.spi.ctlr1.modify(|_, w| w.bidioe().set_bit());
+        self.spi.ctlr1.modify(|_, w| w.spe().set_bit());
+
+        result
+    }
+}
+
+// Implemented per word type, a generic impl would overlap the blanket impl for
+// `write::Default`
+macro_rules! bidi_write {
+    ($($W:ty),+) => {
+        $(
+            impl<SCK, MOSI> embedded_hal_02::blocking::spi::Write<$W> for SpiBidi<SCK, MOSI, $W>
+            where
+                (SCK, NoPin, MOSI): Spi1Pins,
+            {
+                type Error = Error;
+
+                fn write(&mut self, words: &[$W]) -> Result<(), Self::Error> {
+                    SpiBidi::write(self, words)
+                }
+            }
+        )+
+    };
+}
+
+bidi_write!(u8, u16);
+
+/// Marker trait for valid combinations of SCK, MISO and MOSI pins in master mode
+pub trait Spi1Pins {
+    /// Value of the `SPI1_RM` remap bit in AFIO
+    const REMAP: bool;
+}
+
+/// Full duplex
+/// # M
+/// MISO can be floating or pulled
+impl<M> Spi1Pins
+    for (
+        PC5<Alternate<PushPull>>,
+        PC7<Input<M>>,
+        PC6<Alternate<PushPull>>,
+    )
+{
+    const REMAP: bool = false;
+}
+
+/// Transmit only or 3-wire, MISO is left free for other uses
+impl Spi1Pins for (PC5<Alternate<PushPull>>, NoPin, PC6<Alternate<PushPull>>) {
+    const REMAP: bool = false;
+}
+
+/// Receive only, MOSI is left free for other uses
+/// # M
+/// MISO can be floating or pulled
+impl<M> Spi1Pins for (PC5<Alternate<PushPull>>, PC7<Input<M>>, NoPin) {
+    const REMAP: bool = false;
+}
+
+/// SPI peripheral configuration in slave mode
+///
+/// The bus clock is provided by the master, so only the frame format is configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpiSlaveConfig {
+    /// Clock polarity and phase
+    pub mode: Mode,
+    /// Bit order of each frame
+    pub bit_order: BitOrder,
+}
+
+/// Mode 0, MSB first
+impl Default for SpiSlaveConfig {
+    fn default() -> Self {
+        Self {
+            mode: MODE_0,
+            bit_order: BitOrder::MsbFirst,
+        }
+    }
+}
+
+/// Buffers of an interrupt driven chip-select frame
+struct Frame {
+    rx: &'static mut [u8],
+    tx: &'static [u8],
+    pos: usize,
+}
+
+/// Ready to use SPI peripheral in slave mode, selected by the hardware NSS pin
+pub struct SpiSlave<SCK, MISO, MOSI, NSS> {
+    spi: SPI1,
+    sck: SCK,
+    miso: MISO,
+    mosi: MOSI,
+    nss: NSS,
+    frame: Option<Frame>,
+}
+
+impl<SCK, MISO, MOSI, NSS> SpiSlave<SCK, MISO, MOSI, NSS>
+where
+    (SCK, MISO, MOSI, NSS): Spi1SlavePins,
+{
+    /// Initialise the SPI1 peripheral in slave mode with valid SCK, MISO, MOSI and NSS pins
+    pub fn spi1(
+        spi: SPI1,
+        sck: SCK,
+        miso: MISO,
+        mosi: MOSI,
+        nss: NSS,
+        config: SpiSlaveConfig,
+        rcc: &mut Rcc,
+    ) -> Self {
+        // Ensure spi is enabled and reset to known state
+        SPI1::enable(&mut rcc.apb2);
+        SPI1::reset(&mut rcc.apb2);
+
+        AFIO::enable(&mut rcc.apb2);
+
+        // Configure the remap bit in AFIO to match our NSS pin selection
+        unsafe {
+            (*AFIO::ptr()).pcfr.modify(|_, w| {
+                w.spi1rm()
+                    .bit(<(SCK, MISO, MOSI, NSS) as Spi1SlavePins>::REMAP)
+            });
+        }
+
+        spi.ctlr1.write(|w| {
+            w.cpha()
+                .bit(config.mode.phase == Phase::CaptureOnSecondTransition)
+                .cpol()
+                .bit(config.mode.polarity == Polarity::IdleHigh)
+                .mstr() // Slave mode
+                .clear_bit()
+                .lsbfirst()
+                .bit(config.bit_order == BitOrder::LsbFirst)
+                .ssm() // NSS is driven by the master on the NSS pin
+                .clear_bit()
+                .dff() // 8-bit frames
+                .clear_bit()
+        });
+
+        // Start peripheral
+        spi.ctlr1.modify(|_, w| w.spe().set_bit());
+
+        Self {
+            spi,
+            sck,
+            miso,
+            mosi,
+            nss,
+            frame: None,
+        }
+    }
+
+    /// Deconstruct the SPI peripheral and return it's raw hardware resources
+    pub fn release(self) -> (SPI1, SCK, MISO, MOSI, NSS) {
+        self.spi.ctlr2.reset();
+        self.spi.ctlr1.modify(|_, w| w.spe().clear_bit());
+
+        (self.spi, self.sck, self.miso, self.mosi, self.nss)
+    }
+
+    /// Returns true while the master holds NSS low
+    pub fn is_selected(&self) -> bool {
+        let pin = <(SCK, MISO, MOSI, NSS) as Spi1SlavePins>::NSS_PIN;
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { (*crate::pac::GPIOC::ptr()).indr.read().bits() & (1 << pin) == 0 }
+    }
+
+    /// Check the error flags, clearing them if set
+    fn check_error(&mut self) -> Result<(), Error> {
+        let statr = self.spi.statr.read();
+
+        if statr.ovr().bit_is_set() {
+            // Overrun is cleared by reading DATAR followed by STATR
+            let _ = self.spi.datar.read();
+            let _ = self.spi.statr.read();
+            Err(Error::Overrun)
+        } else if statr.modf().bit_is_set() {
+            // Mode fault is cleared by reading STATR followed by a write to CTLR1
+            self.spi.ctlr1.modify(|_, w| w.spe().set_bit());
+            Err(Error::ModeFault)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Try to read a frame received from the master
+    pub fn read(&mut self) -> nb::Result<u8, Error> {
+        self.check_error()?;
+
+        if self.spi.statr.read().rxne().bit_is_set() {
+            Ok(self.spi.datar.read().datar().bits() as u8)
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Try to preload the byte that is shifted out during the next frame
+    ///
+    /// The byte has to be loaded before the master starts clocking, otherwise
+    /// the previous contents of the shift register are sent.
+    pub fn write(&mut self, byte: u8) -> nb::Result<(), Error> {
+        self.check_error()?;
+
+        if self.spi.statr.read().txe().bit_is_set() {
+            self.spi.datar.write(|w| w.datar().variant(byte as u16));
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Start an interrupt driven chip-select frame
+    ///
+    /// Received bytes are stored into `rx` and the bytes of `tx` are sent back, `0x00`
+    /// is sent once `tx` is exhausted. [`SpiSlave::on_interrupt`] has to be called from
+    /// the `SPI1` interrupt handler, and [`SpiSlave::poll_frame`] returns the buffers
+    /// once the master deasserts NSS.
+    ///
+    /// If a byte written with [`SpiSlave::write`] was not clocked out by the master
+    /// yet, the first response can not be preloaded. The frame is not started then,
+    /// and the buffers are returned.
+    pub fn start_frame(
+        &mut self,
+        rx: &'static mut [u8],
+        tx: &'static [u8],
+    ) -> Result<(), (&'static mut [u8], &'static [u8])> {
+        if self.spi.statr.read().txe().bit_is_clear() {
+            return Err((rx, tx));
+        }
+
+        // Drop any stale data and preload the first response byte
+        let _ = self.spi.datar.read();
+        let _ = self.check_error();
+        self.spi.datar.write(|w| {
+            w.datar()
+                .variant(tx.first().copied().unwrap_or(0x00) as u16)
+        });
+
+        self.frame = Some(Frame { rx, tx, pos: 0 });
+
+        self.spi
+            .ctlr2
+            .modify(|_, w| w.rxneie().set_bit().errie().set_bit());
+
+        Ok(())
+    }
+
+    /// Service the `SPI1` interrupt for the frame started with [`SpiSlave::start_frame`]
+    pub fn on_interrupt(&mut self) -> Result<(), Error> {
+        self.check_error()?;
+
+        if self.spi.statr.read().rxne().bit_is_clear() {
+            return Ok(());
+        }
+
+        let byte = self.spi.datar.read().datar().bits() as u8;
+        if let Some(frame) = self.frame.as_mut() {
+            if let Some(slot) = frame.rx.get_mut(frame.pos) {
+                *slot = byte;
+            }
+            frame.pos += 1;
+
+            // Load the response for the next byte, unless the previous one is still
+            // waiting in DATAR
+            if self.spi.statr.read().txe().bit_is_set() {
+                let next = frame.tx.get(frame.pos).copied().unwrap_or(0x00);
+                self.spi.datar.write(|w| w.datar().variant(next as u16));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the receive buffer and the number of bytes clocked in, once the master
+    /// has deselected the slave
+    ///
+    /// The count may exceed the buffer length if the master clocked more bytes than fit.
+    pub fn poll_frame(&mut self) -> nb::Result<(&'static mut [u8], usize), Error> {
+        match &self.frame {
+            Some(frame) if frame.pos > 0 && !self.is_selected() => {}
+            _ => return Err(nb::Error::WouldBlock),
+        }
+
+        self.spi
+            .ctlr2
+            .modify(|_, w| w.rxneie().clear_bit().errie().clear_bit());
+
+        let frame = self.frame.take().unwrap();
+        Ok((frame.rx, frame.pos))
+    }
+
+    /// Abort the frame in progress, returning the receive buffer
+    pub fn cancel_frame(&mut self) -> Option<&'static mut [u8]> {
+        self.spi
+            .ctlr2
+            .modify(|_, w| w.rxneie().clear_bit().errie().clear_bit());
+
+        self.frame.take().map(|frame| frame.rx)
+    }
+}
+
+/// Marker trait for valid combinations of SCK, MISO, MOSI and NSS pins in slave mode
+pub trait Spi1SlavePins {
+    /// Value of the `SPI1_RM` remap bit in AFIO
+    const REMAP: bool;
+    /// Pin number of NSS on GPIOC
+    const NSS_PIN: u8;
+}
+
+/// Default pin remapping option (NSS on PC1)
+impl<S, M, N> Spi1SlavePins
+    for (
+        PC5<Input<S>>,
+        PC7<Alternate<PushPull>>,
+        PC6<Input<M>>,
+        PC1<Input<N>>,
+    )
+{
+    const REMAP: bool = false;
+    const NSS_PIN: u8 = 1;
+}
+
+/// Pin remapping option 1 (NSS on PC0)
+impl<S, M, N> Spi1SlavePins
+    for (
+        PC5<Input<S>>,
+        PC7<Alternate<PushPull>>,
+        PC6<Input<M>>,
+        PC0<Input<N>>,
+    )
+{
+    const REMAP: bool = true;
+    const NSS_PIN: u8 = 0;
+}
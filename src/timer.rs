@@ -33,7 +33,7 @@ use crate::pac::SYSTICK;
 
 use ch32v0::ch32v003::{TIM1, TIM2};
 pub mod pwm_input;
-pub use pwm_input::*;
+pub use pwm_input::{Configuration, PwmInput, ReadMode};
 pub(crate) mod pins;
 pub use pins::*;
 pub mod delay;
@@ -259,12 +259,10 @@ impl Timer<SYSTICK> {
     pub fn syst(mut tim: SYSTICK, clocks: &Clocks) -> Self {
         tim.set_clock_source(SystickClkSource::HClk);
         
-        let t = Self {
+        Self {
             tim,
             clk: clocks.hclk(),
-        };
-
-        t
+        }
     }
 
     pub fn configure(&mut self, clocks: &Clocks) {
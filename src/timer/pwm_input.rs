@@ -4,7 +4,6 @@
 use core::marker::PhantomData;
 use core::mem;
 
-use crate::pac::DBG;
 use crate::pac::{TIM1, TIM2};
 
 use crate::gpio::{self, Input};
@@ -74,21 +73,18 @@ pub enum Configuration {
     RawValues { arr: u16, presc: u16 },
 }
 
-#[cfg(any(feature = "stm32f100", feature = "stm32f103", feature = "connectivity",))]
 impl Timer<TIM1> {
     pub fn pwm_input<REMAP, PINS>(
-        mut self,
+        self,
         pins: PINS,
-        pcfr: &mut PCFR,
-        dbg: &mut DBG,
         mode: Configuration,
     ) -> PwmInput<TIM1, REMAP, PINS>
     where
         REMAP: Remap<Periph = TIM1>,
         PINS: Pins<REMAP>,
     {
-        REMAP::remap(pcfr);
-        self.stop_in_debug(dbg, false);
+        REMAP::remap();
+
         let Self { tim, clk } = self;
         tim1(tim, pins, clk, mode)
     }
@@ -96,7 +92,7 @@ impl Timer<TIM1> {
 
 impl Timer<TIM2> {
     pub fn pwm_input<REMAP, PINS>(
-        mut self,
+        self,
         pins: PINS,
         mode: Configuration,
     ) -> PwmInput<TIM2, REMAP, PINS>
@@ -115,9 +111,9 @@ impl Timer<TIM2> {
 /// Courtesy of @TeXitoi (https://github.com/stm32-rs/stm32f1xx-hal/pull/10#discussion_r259535503)
 fn compute_arr_presc(freq: u32, clock: u32) -> (u16, u16) {
     if freq == 0 {
-        return (core::u16::MAX, core::u16::MAX);
+        return (u16::MAX, u16::MAX);
     }
-    let presc = clock / freq.saturating_mul(core::u16::MAX as u32 + 1);
+    let presc = clock / freq.saturating_mul(u16::MAX as u32 + 1);
     let arr = clock / freq.saturating_mul(presc + 1);
     (core::cmp::max(1, arr as u16), presc as u16)
 }
@@ -1,6 +1,11 @@
 //! Watchdog peripherals
 
-use crate::{ pac::IWDG, time::MilliSeconds };
+use crate::{
+    pac::{IWDG, WWDG},
+    rcc::{BusClock, Clocks, Enable, Rcc, Reset, ResetReason},
+    time::{Hertz, MilliSeconds},
+};
+use embedded_hal_02::watchdog::{Watchdog, WatchdogEnable};
 use fugit::ExtU32;
 
 /// Wraps the Independent Watchdog (IWDG) peripheral
@@ -87,4 +92,149 @@ impl IndependentWatchdog {
     pub fn feed(&mut self) {
         self.iwdg.ctlr.write(|w| unsafe { w.key().bits(KR_RELOAD) });
     }
+
+    /// Returns `true` if the IWDG reset flag is set
+    ///
+    /// See [`ResetReason`] to read and clear all reset flags.
+    pub fn caused_reset() -> bool {
+        ResetReason::read().contains(ResetReason::IndependentWatchdog)
+    }
+}
+
+/// Wraps the Window Watchdog (WWDG) peripheral
+///
+/// The watchdog counter is clocked from PCLK1 / 4096 / 2^WDGTB and resets the chip
+/// when it runs out, or when it is fed while the window is still closed.
+pub struct WindowWatchdog {
+    wwdg: WWDG,
+    pclk: Hertz,
+    /// Counter value written on every feed
+    reload: u8,
+}
+
+/// The chip resets when the counter decrements below this value
+const WWDG_T_MIN: u8 = 0x40;
+const WWDG_T_MAX: u8 = 0x7F;
+const WWDG_MAX_TB: u8 = 3;
+
+impl WindowWatchdog {
+    /// Enable the WWDG clock, the watchdog only starts running with `start`
+    pub fn new(wwdg: WWDG, rcc: &mut Rcc, clocks: &Clocks) -> Self {
+        WWDG::enable(&mut rcc.apb1);
+        WWDG::reset(&mut rcc.apb1);
+
+        WindowWatchdog {
+            wwdg,
+            pclk: WWDG::clock(clocks),
+            reload: WWDG_T_MAX,
+        }
+    }
+
+    /// Duration of one counter tick with prescaler `tb`, in microseconds
+    fn tick_us(&self, tb: u8) -> u32 {
+        let div = 4096u64 << tb;
+        (div * 1_000_000 / self.pclk.raw() as u64) as u32
+    }
+
+    /// Returns the longest timeout supported with the current PCLK1 frequency
+    ///
+    /// This is 64 ticks of PCLK1 / 32768, about 87ms at 24MHz.
+    pub fn max_timeout(&self) -> MilliSeconds {
+        (self.tick_us(WWDG_MAX_TB) * 64 / 1000).millis()
+    }
+
+    /// Start the watchdog, it can be fed at any time before `timeout`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timeout` is above [`max_timeout`](Self::max_timeout).
+    pub fn start(&mut self, timeout: MilliSeconds) {
+        self.start_windowed(0.millis(), timeout);
+    }
+
+    /// Start the watchdog, it must be fed between `window` and `timeout` after the
+    /// previous feed
+    ///
+    /// Both durations are rounded to the watchdog's resolution. Feeding before `window`
+    /// resets the chip, as does missing `timeout`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timeout` is above [`max_timeout`](Self::max_timeout).
+    pub fn start_windowed(&mut self, window: MilliSeconds, timeout: MilliSeconds) {
+        assert!(timeout <= self.max_timeout());
+        let timeout_us = timeout.ticks() * 1000;
+
+        // Smallest prescaler whose 64 ticks cover the timeout
+        let mut tb = 0;
+        while tb < WWDG_MAX_TB && self.tick_us(tb) * 64 < timeout_us {
+            tb += 1;
+        }
+        let tick_us = self.tick_us(tb);
+
+        let ticks = (timeout_us / tick_us).clamp(1, 64) as u8;
+        let reload = WWDG_T_MIN + ticks - 1;
+
+        let closed = (window.ticks() * 1000 / tick_us).min(ticks as u32) as u8;
+        let window = (reload - closed).max(WWDG_T_MIN);
+
+        self.reload = reload;
+        self.wwdg
+            .cfgr
+            .modify(|_, w| unsafe { w.wdgtb().bits(tb).w().bits(window) });
+        self.wwdg
+            .ctlr
+            .write(|w| unsafe { w.t().bits(reload).wdga().set_bit() });
+    }
+
+    /// Reload the counter
+    pub fn feed(&mut self) {
+        self.wwdg.ctlr.write(|w| unsafe { w.t().bits(self.reload) });
+    }
+
+    /// Returns the timeout set by `start`
+    pub fn interval(&self) -> MilliSeconds {
+        let tb = self.wwdg.cfgr.read().wdgtb().bits();
+        let ticks = u32::from(self.reload - WWDG_T_MIN + 1);
+        (ticks * self.tick_us(tb) / 1000).millis()
+    }
+
+    /// Enable the early wakeup interrupt, raised one tick before the reset
+    ///
+    /// It can only be disabled by a reset. Feed the watchdog or clear the flag in the
+    /// WWDG interrupt handler.
+    pub fn listen(&mut self) {
+        self.wwdg.cfgr.modify(|_, w| w.ewi().set_bit());
+    }
+
+    /// Returns `true` if the early wakeup flag is set
+    pub fn is_early_wakeup(&self) -> bool {
+        self.wwdg.statr.read().weif().bit_is_set()
+    }
+
+    /// Clear the early wakeup flag
+    pub fn clear_early_wakeup(&mut self) {
+        self.wwdg.statr.write(|w| w.weif().clear_bit());
+    }
+
+    /// Returns `true` if the WWDG reset flag is set
+    ///
+    /// See [`ResetReason`] to read and clear all reset flags.
+    pub fn caused_reset() -> bool {
+        ResetReason::read().contains(ResetReason::WindowWatchdog)
+    }
+}
+
+impl Watchdog for WindowWatchdog {
+    fn feed(&mut self) {
+        self.feed()
+    }
+}
+
+impl WatchdogEnable for WindowWatchdog {
+    type Time = MilliSeconds;
+
+    fn start<T: Into<Self::Time>>(&mut self, period: T) {
+        self.start(period.into())
+    }
 }